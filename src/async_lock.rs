@@ -0,0 +1,295 @@
+//! Lock acquisition that doesn't block the calling task
+
+use crate::{FileLock, FileLockBuilder};
+#[cfg(feature = "futures-io")]
+use std::io::IoSliceMut;
+#[cfg(any(feature = "futures-io", feature = "tokio"))]
+use std::io::{prelude::*, IoSlice, SeekFrom};
+use std::{
+    future::Future,
+    io::Result,
+    ops::{Deref, DerefMut},
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::Path,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+/// A [`FileLock`](struct.FileLock.html) acquired without blocking the
+/// executor
+///
+/// Waiting for a contended lock happens on a helper thread, and the task is
+/// woken once the lock is granted, so worker threads stay free in the
+/// meantime. Only the standard library's `Future` and `Waker` are used, so
+/// this works under any executor: Tokio, async-std, smol or a hand-written
+/// one.
+///
+/// Once acquired, it derefs to the `FileLock`, and releases the lock when
+/// dropped like the `FileLock` does. With the `futures-io` or `tokio`
+/// feature, it also implements that crate's `AsyncRead`, `AsyncWrite` and
+/// `AsyncSeek` over the file, e.g. to `copy()` into it. Like `File` in
+/// `Read` and `Write`, these do the I/O right away on the calling thread:
+/// regular files are always ready, and no other process waits on them
+/// while the lock is held.
+///
+/// # Examples
+///
+///```no_run
+///use file_locker::AsyncFileLock;
+///use std::io::Result;
+///
+///async fn update() -> Result<()> {
+///    let filelock = AsyncFileLock::lock("myfile.txt", true).await?;
+///    Ok(())
+///}
+///```
+#[derive(Debug)]
+pub struct AsyncFileLock {
+    filelock: FileLock,
+}
+
+impl AsyncFileLock {
+    /// Wait for a lock on the whole file, exclusive if `writeable`, shared
+    /// otherwise
+    ///
+    /// Use [`FileLockBuilder::lock_async`](struct.FileLockBuilder.html#method.lock_async)
+    /// for the other options.
+    pub fn lock(
+        file_path: impl AsRef<Path> + Send + 'static,
+        writeable: bool,
+    ) -> LockFuture {
+        FileLock::new(file_path)
+            .blocking(true)
+            .writeable(writeable)
+            .lock_async()
+    }
+
+    /// The underlying `FileLock`, still locked
+    pub fn into_inner(self) -> FileLock {
+        self.filelock
+    }
+}
+
+impl Deref for AsyncFileLock {
+    type Target = FileLock;
+
+    fn deref(&self) -> &FileLock {
+        &self.filelock
+    }
+}
+
+impl DerefMut for AsyncFileLock {
+    fn deref_mut(&mut self) -> &mut FileLock {
+        &mut self.filelock
+    }
+}
+
+impl AsRawFd for AsyncFileLock {
+    fn as_raw_fd(&self) -> RawFd {
+        self.filelock.as_raw_fd()
+    }
+}
+
+impl AsFd for AsyncFileLock {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.filelock.as_fd()
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncRead for AsyncFileLock {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().filelock.read(buf))
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().filelock.read_vectored(bufs))
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncWrite for AsyncFileLock {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().filelock.write(buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().filelock.write_vectored(bufs))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        Poll::Ready(self.get_mut().filelock.flush())
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        Poll::Ready(self.get_mut().filelock.flush())
+    }
+}
+
+#[cfg(feature = "futures-io")]
+impl futures_io::AsyncSeek for AsyncFileLock {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<Result<u64>> {
+        Poll::Ready(self.get_mut().filelock.seek(pos))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncRead for AsyncFileLock {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let n = self.get_mut().filelock.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncWrite for AsyncFileLock {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().filelock.write(buf))
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        Poll::Ready(self.get_mut().filelock.write_vectored(bufs))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        Poll::Ready(self.get_mut().filelock.flush())
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<()>> {
+        Poll::Ready(self.get_mut().filelock.flush())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl tokio::io::AsyncSeek for AsyncFileLock {
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> Result<()> {
+        self.get_mut().filelock.seek(pos).map(drop)
+    }
+
+    fn poll_complete(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<u64>> {
+        // the seek already happened in start_seek
+        Poll::Ready(self.get_mut().filelock.stream_position())
+    }
+}
+
+type Acquire = Box<dyn FnOnce() -> Result<FileLock> + Send>;
+
+#[derive(Default)]
+struct State {
+    result: Option<Result<FileLock>>,
+    waker: Option<Waker>,
+}
+
+/// Future returned by [`AsyncFileLock::lock`](struct.AsyncFileLock.html#method.lock)
+/// and [`FileLockBuilder::lock_async`](struct.FileLockBuilder.html#method.lock_async)
+///
+/// Nothing happens until it is first polled. If it is dropped while the
+/// helper thread is still waiting, the thread keeps waiting and releases
+/// the lock as soon as it gets it.
+pub struct LockFuture {
+    acquire: Option<Acquire>,
+    state: Arc<Mutex<State>>,
+}
+
+impl LockFuture {
+    pub(crate) fn new<T>(builder: FileLockBuilder<T>) -> LockFuture
+    where
+        T: AsRef<Path> + Send + 'static,
+    {
+        LockFuture {
+            acquire: Some(Box::new(move || builder.lock())),
+            state: Arc::default(),
+        }
+    }
+}
+
+impl Future for LockFuture {
+    type Output = Result<AsyncFileLock>;
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(result) = state.result.take() {
+            return Poll::Ready(
+                result.map(|filelock| AsyncFileLock { filelock }),
+            );
+        }
+        state.waker = Some(cx.waker().clone());
+        drop(state);
+
+        if let Some(acquire) = self.acquire.take() {
+            let shared = self.state.clone();
+            let spawned = thread::Builder::new()
+                .name("file-locker".into())
+                .spawn(move || {
+                    let result = acquire();
+                    let mut state =
+                        shared.lock().unwrap_or_else(|e| e.into_inner());
+                    state.result = Some(result);
+                    if let Some(waker) = state.waker.take() {
+                        waker.wake();
+                    }
+                });
+            if let Err(e) = spawned {
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,81 @@
+//! Lock events pushed to a user-supplied channel
+
+use crate::{fd_path, LockType};
+use std::{
+    os::unix::io::RawFd,
+    path::PathBuf,
+    sync::{mpsc::Sender, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+/// What happened in a [`LockEvent`](struct.LockEvent.html)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockEventKind {
+    /// A lock is about to be requested
+    Attempt,
+    /// A blocking request found the range held and is now waiting
+    Blocked,
+    /// The lock was granted
+    Acquired,
+    /// The range was released
+    Released,
+}
+
+/// A lock change, as sent to the sink set with
+/// [`set_event_sink`](fn.set_event_sink.html)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockEvent {
+    /// what happened
+    pub kind: LockEventKind,
+    /// the locked file, as resolved from its descriptor
+    pub path: PathBuf,
+    /// offset of the first byte of the range
+    pub start: u64,
+    /// length of the range, 0 meaning up to the end of the file
+    pub len: u64,
+    /// lock requested, `Unlocked` for `Released`
+    pub lock_type: LockType,
+    /// when it happened
+    pub time: SystemTime,
+}
+
+static SINK: Mutex<Option<Sender<LockEvent>>> = Mutex::new(None);
+
+fn sink() -> MutexGuard<'static, Option<Sender<LockEvent>>> {
+    SINK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn set_sink(sender: Option<Sender<LockEvent>>) {
+    *sink() = sender;
+}
+
+/// Whether anyone listens, so callers can skip work otherwise
+pub(crate) fn enabled() -> bool {
+    sink().is_some()
+}
+
+pub(crate) fn emit(
+    kind: LockEventKind,
+    fd: RawFd,
+    start: u64,
+    end: Option<u64>,
+    lock_type: LockType,
+) {
+    let mut sink = sink();
+    let sender = match &*sink {
+        Some(sender) => sender,
+        None => return,
+    };
+    let event = LockEvent {
+        kind,
+        path: fd_path(fd),
+        start,
+        len: end.map_or(0, |end| end - start),
+        lock_type,
+        time: SystemTime::now(),
+    };
+    // the receiver is gone, stop building events for nobody
+    if sender.send(event).is_err() {
+        *sink = None;
+    }
+}
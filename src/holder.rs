@@ -0,0 +1,108 @@
+//! Who holds a lock, written into the locked file
+
+use crate::cver;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Description of a lock holder, as written into the locked file by
+/// [`FileLockBuilder::record_holder`](struct.FileLockBuilder.html#method.record_holder)
+///
+/// The file holds one `key=value` line per field, so it can also be read by
+/// operators and scripts. With the hostname and boot id, the holder can be
+/// told apart even when machines share the filesystem. With the `serde`
+/// feature it implements `serde::Serialize`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HolderInfo {
+    /// PID of the holding process
+    pub pid: u32,
+    /// host the holder runs on
+    pub hostname: String,
+    /// identifies the boot of the host, on Linux
+    pub boot_id: Option<String>,
+    /// when the lock was taken
+    pub time: SystemTime,
+    /// what the holder said it is, on a single line
+    pub label: String,
+}
+
+impl HolderInfo {
+    /// Describe this process, with `label`
+    pub(crate) fn current(label: &str) -> Result<HolderInfo> {
+        let mut buf = [0; 256];
+        let hostname = nix::unistd::gethostname(&mut buf)
+            .map_err(cver)?
+            .to_string_lossy()
+            .into_owned();
+        #[cfg(target_os = "linux")]
+        let boot_id =
+            std::fs::read_to_string("/proc/sys/kernel/random/boot_id")
+                .ok()
+                .map(|id| id.trim().to_string());
+        #[cfg(not(target_os = "linux"))]
+        let boot_id = None;
+        Ok(HolderInfo {
+            pid: std::process::id(),
+            hostname,
+            boot_id,
+            time: SystemTime::now(),
+            label: label.replace(['\r', '\n'], " "),
+        })
+    }
+
+    /// Read what the holder of the file at `path` wrote into it, `None` if
+    /// it doesn't hold a description
+    ///
+    /// *Note:* reading the file opens and closes it, which releases the
+    /// locks this process holds on it through other handles. Use
+    /// [`FileLock::holder_info`](struct.FileLock.html#method.holder_info)
+    /// on a handle of this process instead.
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<HolderInfo>> {
+        match std::fs::read(path) {
+            Ok(contents) => Ok(HolderInfo::parse(&contents)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub(crate) fn parse(contents: &[u8]) -> Option<HolderInfo> {
+        let contents = std::str::from_utf8(contents).ok()?;
+        let (mut pid, mut hostname, mut time) = (None, None, None);
+        let (mut boot_id, mut label) = (None, String::new());
+        for line in contents.lines() {
+            let (key, value) = line.split_once('=')?;
+            match key {
+                "pid" => pid = Some(value.parse().ok()?),
+                "hostname" => hostname = Some(value.to_string()),
+                "boot_id" => boot_id = Some(value.to_string()),
+                "time" => time = Some(value.parse().ok()?),
+                "label" => label = value.to_string(),
+                _ => {}
+            }
+        }
+        Some(HolderInfo {
+            pid: pid?,
+            hostname: hostname?,
+            boot_id,
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(time?),
+            label,
+        })
+    }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        let time = self
+            .time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let mut contents =
+            format!("pid={}\nhostname={}\n", self.pid, self.hostname);
+        if let Some(boot_id) = &self.boot_id {
+            contents += &format!("boot_id={}\n", boot_id);
+        }
+        contents += &format!("time={}\nlabel={}\n", time.as_secs(), self.label);
+        Ok(contents.into_bytes())
+    }
+}
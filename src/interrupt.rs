@@ -0,0 +1,371 @@
+//! Bounded `F_SETLKW` waits
+//!
+//! The wait runs on a helper thread. When the caller gives up, the helper is
+//! sent `WAKE_SIGNAL`, whose handler does nothing but is installed without
+//! `SA_RESTART`, so the pending `fcntl()` fails with `EINTR` and the helper
+//! can return. Waits can also be cancelled from another thread with a
+//! [`CancelToken`](struct.CancelToken.html).
+//!
+//! The handler is only installed if `WAKE_SIGNAL` has its default
+//! disposition. If the application ignores the signal, or handles it with
+//! `SA_RESTART`, the signal can't interrupt `fcntl()`, so the wait polls with
+//! `F_SETLK` every `RESEND_INTERVAL` instead.
+
+use crate::{cver, flock_arg, OnInterrupt};
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, flock, FcntlArg},
+    libc,
+    sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal},
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{Error, ErrorKind, Result},
+    os::unix::io::{AsRawFd, RawFd},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Signal used to interrupt a waiting helper thread. Its default action is
+/// to be ignored, so it's rarely used by applications.
+pub(crate) const WAKE_SIGNAL: Signal = Signal::SIGURG;
+
+/// How often the helper is signalled again until it notices it should stop,
+/// in case the first signal arrived before it entered `fcntl()`
+const RESEND_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Cap applied to blocking waits that don't have a timeout of their own
+static DEFAULT_MAX_WAIT: Mutex<Option<Duration>> = Mutex::new(None);
+
+pub(crate) fn set_default_max_wait(max_wait: Option<Duration>) {
+    *DEFAULT_MAX_WAIT.lock().unwrap_or_else(|e| e.into_inner()) = max_wait;
+}
+
+extern "C" fn wake(_: libc::c_int) {}
+
+/// Install the no-op `WAKE_SIGNAL` handler if the signal still has its
+/// default disposition, returning whether the signal interrupts `fcntl()`
+fn install_handler() -> bool {
+    let mut old: libc::sigaction = unsafe { std::mem::zeroed() };
+    let signum = WAKE_SIGNAL as libc::c_int;
+    unsafe { libc::sigaction(signum, std::ptr::null(), &mut old) };
+    match old.sa_sigaction {
+        libc::SIG_DFL => {
+            let action = SigAction::new(
+                SigHandler::Handler(wake),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            let _ = unsafe { signal::sigaction(WAKE_SIGNAL, &action) };
+            true
+        }
+        libc::SIG_IGN => false,
+        _ => old.sa_flags & libc::SA_RESTART == 0,
+    }
+}
+
+/// Which lock command to wait in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Wait {
+    /// `F_SETLKW`, for process-associated locks
+    Setlkw,
+    /// `F_OFD_SETLKW`, for open file description locks
+    #[cfg(target_os = "linux")]
+    OfdSetlkw,
+    /// `flock()` without `LOCK_NB`, for whole-file BSD locks
+    Flock,
+}
+
+impl Wait {
+    fn wait(self, fd: RawFd, lock: &libc::flock) -> nix::Result<()> {
+        match self {
+            Wait::Setlkw => fcntl(fd, FcntlArg::F_SETLKW(lock)).map(drop),
+            #[cfg(target_os = "linux")]
+            Wait::OfdSetlkw => {
+                fcntl(fd, FcntlArg::F_OFD_SETLKW(lock)).map(drop)
+            }
+            Wait::Flock => flock(fd, flock_arg(lock, true)),
+        }
+    }
+
+    /// The non-blocking version of the command
+    ///
+    /// Contention is always reported as `EAGAIN`, i.e.
+    /// `ErrorKind::WouldBlock`, although POSIX lets `F_SETLK` fail with
+    /// `EACCES` instead.
+    pub(crate) fn try_once(self, fd: RawFd, lock: &libc::flock) -> Result<()> {
+        match self {
+            Wait::Setlkw => fcntl(fd, FcntlArg::F_SETLK(lock)).map(drop),
+            #[cfg(target_os = "linux")]
+            Wait::OfdSetlkw => fcntl(fd, FcntlArg::F_OFD_SETLK(lock)).map(drop),
+            Wait::Flock => flock(fd, flock_arg(lock, false)),
+        }
+        .map_err(|e| match e {
+            Errno::EACCES => cver(Errno::EAGAIN),
+            e => cver(e),
+        })
+    }
+}
+
+/// Handle to abort blocking lock waits from another thread
+///
+/// Pass a clone to [`FileLockBuilder::cancel_token`](struct.FileLockBuilder.html#method.cancel_token)
+/// and call [`cancel`](#method.cancel) from anywhere, e.g. a shutdown
+/// handler: the waits using it stop with an error of kind
+/// `ErrorKind::Interrupted`, and any later wait using it fails straight
+/// away. The waiting thread is woken by a signal, the same way as for
+/// [`lock_timeout`](struct.FileLockBuilder.html#method.lock_timeout).
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    inner: Arc<CancelState>,
+}
+
+#[derive(Debug, Default)]
+struct CancelState {
+    cancelled: AtomicBool,
+    waiters: Mutex<Waiters>,
+}
+
+#[derive(Debug, Default)]
+struct Waiters {
+    next: u64,
+    senders: BTreeMap<u64, Sender<Message>>,
+}
+
+impl CancelToken {
+    /// A token that hasn't been cancelled yet
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Abort every wait using this token, now and in the future
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let mut waiters = self.waiters();
+        for (_, sender) in std::mem::take(&mut waiters.senders) {
+            let _ = sender.send(Message::Cancel);
+        }
+    }
+
+    /// Whether [`cancel`](#method.cancel) was called
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn waiters(&self) -> MutexGuard<'_, Waiters> {
+        self.inner.waiters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Have `sender` told when the token is cancelled, unless it already is
+    fn register(&self, sender: Sender<Message>) -> Option<u64> {
+        let mut waiters = self.waiters();
+        // checked under the lock, so `cancel` either sees `sender` or has
+        // set the flag already
+        if self.is_cancelled() {
+            return None;
+        }
+        let id = waiters.next;
+        waiters.next += 1;
+        waiters.senders.insert(id, sender);
+        Some(id)
+    }
+
+    fn unregister(&self, id: u64) {
+        self.waiters().senders.remove(&id);
+    }
+}
+
+enum Message {
+    Done(Result<()>),
+    Cancel,
+}
+
+fn cancelled_error() -> Error {
+    Error::new(ErrorKind::Interrupted, "lock wait cancelled")
+}
+
+fn timed_out_error(started: Instant) -> Error {
+    Error::new(
+        ErrorKind::TimedOut,
+        format!("timed out waiting for lock after {:?}", started.elapsed()),
+    )
+}
+
+/// Wait for `flock` to be applied to `file` with `wait`, giving up with
+/// `ErrorKind::TimedOut` after `timeout`, the error telling how long was
+/// actually waited
+pub(crate) fn setlkw_timeout(
+    file: &File,
+    flock: &libc::flock,
+    wait: Wait,
+    timeout: Duration,
+) -> Result<()> {
+    setlkw_until(file, flock, wait, Some(timeout), None)
+}
+
+/// Wait for `flock` to be applied to `file` with `wait`, for at most
+/// `timeout` or else the default maximum wait, and until `cancel` is
+/// cancelled
+pub(crate) fn setlkw_cancellable(
+    file: &File,
+    flock: &libc::flock,
+    wait: Wait,
+    timeout: Option<Duration>,
+    cancel: &CancelToken,
+) -> Result<()> {
+    let timeout = timeout.or_else(default_max_wait);
+    setlkw_until(file, flock, wait, timeout, Some(cancel))
+}
+
+fn setlkw_until(
+    file: &File,
+    flock: &libc::flock,
+    wait: Wait,
+    timeout: Option<Duration>,
+    cancel: Option<&CancelToken>,
+) -> Result<()> {
+    if cancel.map_or(false, CancelToken::is_cancelled) {
+        return Err(cancelled_error());
+    }
+    // no helper thread needed if nobody is in the way, and even a zero
+    // timeout gets one attempt
+    match wait.try_once(file.as_raw_fd(), flock) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+        result => return result,
+    }
+
+    let started = Instant::now();
+    let deadline = timeout.map(|timeout| started + timeout);
+    if !install_handler() {
+        return poll_until(file, flock, wait, started, deadline, cancel);
+    }
+    let stop = AtomicBool::new(false);
+    let by_token = AtomicBool::new(false);
+
+    thread::scope(|scope| {
+        let (thread_tx, thread_rx) = mpsc::channel();
+        let (result_tx, result_rx) = mpsc::channel();
+        let registered = match cancel {
+            Some(token) => match token.register(result_tx.clone()) {
+                Some(id) => Some((token, id)),
+                None => return Err(cancelled_error()),
+            },
+            None => None,
+        };
+        let (stop, by_token) = (&stop, &by_token);
+        scope.spawn(move || {
+            let mut unblock = SigSet::empty();
+            unblock.add(WAKE_SIGNAL);
+            let _ = unblock.thread_unblock();
+            let _ = thread_tx.send(unsafe { libc::pthread_self() });
+            let result = loop {
+                if stop.load(Ordering::SeqCst) {
+                    if by_token.load(Ordering::SeqCst) {
+                        break Err(cancelled_error());
+                    }
+                    break Err(timed_out_error(started));
+                }
+                match wait.wait(file.as_raw_fd(), flock) {
+                    Err(Errno::EINTR) => continue,
+                    result => break result.map_err(cver),
+                }
+            };
+            let _ = result_tx.send(Message::Done(result));
+        });
+
+        // the helper is only joined when the scope ends, so its pthread_t
+        // stays valid to signal until then
+        let helper = thread_rx.recv().expect("lock helper thread died");
+        let mut wait = deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let result = loop {
+            let message = match wait {
+                Some(wait) => result_rx.recv_timeout(wait),
+                None => {
+                    result_rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                }
+            };
+            match message {
+                Ok(Message::Done(result)) => break result,
+                Ok(Message::Cancel) => by_token.store(true, Ordering::SeqCst),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    break Err(Error::new(
+                        ErrorKind::Other,
+                        "lock helper thread died",
+                    ))
+                }
+            }
+            stop.store(true, Ordering::SeqCst);
+            unsafe { libc::pthread_kill(helper, WAKE_SIGNAL as libc::c_int) };
+            wait = Some(RESEND_INTERVAL);
+        };
+        if let Some((token, id)) = registered {
+            token.unregister(id);
+        }
+        result
+    })
+}
+
+/// Retry `flock` every `RESEND_INTERVAL` until `deadline`, for when
+/// `WAKE_SIGNAL` can't interrupt a waiting helper
+fn poll_until(
+    file: &File,
+    flock: &libc::flock,
+    wait: Wait,
+    started: Instant,
+    deadline: Option<Instant>,
+    cancel: Option<&CancelToken>,
+) -> Result<()> {
+    loop {
+        if cancel.map_or(false, CancelToken::is_cancelled) {
+            return Err(cancelled_error());
+        }
+        let mut interval = RESEND_INTERVAL;
+        if let Some(deadline) = deadline {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left == Duration::ZERO {
+                return Err(timed_out_error(started));
+            }
+            interval = interval.min(left);
+        }
+        thread::sleep(interval);
+        match wait.try_once(file.as_raw_fd(), flock) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+    }
+}
+
+pub(crate) fn default_max_wait() -> Option<Duration> {
+    *DEFAULT_MAX_WAIT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Wait for `flock` to be applied to `file` with `wait`, for at most the
+/// default maximum wait if one is set, handling `EINTR` as `on_interrupt`
+/// says otherwise
+pub(crate) fn setlkw(
+    file: &File,
+    flock: &libc::flock,
+    wait: Wait,
+    on_interrupt: OnInterrupt,
+) -> Result<()> {
+    if let Some(timeout) = default_max_wait() {
+        return setlkw_timeout(file, flock, wait, timeout);
+    }
+    let mut interrupted = 0;
+    loop {
+        match wait.wait(file.as_raw_fd(), flock) {
+            Err(Errno::EINTR) if on_interrupt.retry(interrupted) => {
+                interrupted += 1;
+            }
+            result => return result.map_err(cver),
+        }
+    }
+}
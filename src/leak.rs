@@ -0,0 +1,62 @@
+//! Registry of held locks, to find locks left held by mistake in tests
+
+use crate::{fd_path, ranges::RangeMap};
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeMap,
+    os::unix::io::RawFd,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+/// A `FileLock` that still holds a lock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakRecord {
+    /// The locked file, as resolved from its descriptor when first locked
+    pub path: PathBuf,
+    /// The `(start, len)` ranges currently held, a `len` of 0 meaning up to
+    /// the end of the file
+    pub ranges: Vec<(u64, u64)>,
+    /// Where the handle first acquired a lock
+    pub backtrace: String,
+}
+
+// keyed by descriptor, which is unique among the open files of the process
+static REGISTRY: Mutex<BTreeMap<RawFd, LeakRecord>> =
+    Mutex::new(BTreeMap::new());
+
+fn registry() -> MutexGuard<'static, BTreeMap<RawFd, LeakRecord>> {
+    REGISTRY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Track what `fd` holds after a lock change
+pub(crate) fn update(fd: RawFd, held: &RangeMap) {
+    let mut registry = registry();
+    if held.is_empty() {
+        registry.remove(&fd);
+        return;
+    }
+    let ranges = held
+        .iter()
+        .map(|range| {
+            (range.start, range.end.map_or(0, |end| end - range.start))
+        })
+        .collect();
+    registry
+        .entry(fd)
+        .or_insert_with(|| LeakRecord {
+            path: fd_path(fd),
+            ranges: Vec::new(),
+            backtrace: Backtrace::force_capture().to_string(),
+        })
+        .ranges = ranges;
+}
+
+/// Stop tracking `fd`, which is about to be closed
+pub(crate) fn forget(fd: RawFd) {
+    registry().remove(&fd);
+}
+
+pub(crate) fn leaked_locks() -> Vec<LeakRecord> {
+    registry().values().cloned().collect()
+}
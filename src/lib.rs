@@ -21,33 +21,328 @@
 //!                         .writeable(true)
 //!                         .lock()?;
 //!
-//!     filelock.file.write_all(b"Hello, World!")?;
+//!     filelock.write_all(b"Hello, World!")?;
 //!
 //!     // Manually unlocking is optional as we unlock on Drop
 //!     filelock.unlock()?;
 //!     Ok(())
 //! }
 //! ```
+//!
+//! # Async
+//!
+//! With the `async` feature, [`AsyncFileLock`](struct.AsyncFileLock.html)
+//! and `FileLockBuilder::lock_async` wait for contended locks on a helper
+//! thread and wake the task when the lock is granted. They only rely on the
+//! standard library's `Future` and `Waker`, so they work the same under
+//! Tokio, async-std, smol or any other executor, without pulling in a
+//! runtime.
+//!
+//! The `futures-io` and `tokio` features additionally implement those
+//! crates' `AsyncRead`, `AsyncWrite` and `AsyncSeek` for `AsyncFileLock`.
+//!
+//! # Migrating to 2.0
+//!
+//! The `file` field of [`FileLock`](struct.FileLock.html) is now private, as
+//! taking the `File` out of the lock and closing it silently released the
+//! lock. `FileLock` implements `Read`, `Write`, `Seek` and derefs to `File`,
+//! so most code only needs to drop the `.file`. Otherwise:
+//!
+//! - `&filelock.file` becomes `filelock.file()`
+//! - `&mut filelock.file` becomes `filelock.file_mut()`
+//! - moving `filelock.file` out becomes `filelock.into_inner()?`, which
+//!   releases the lock first
+//!
+//! `FileLock::lock(path, blocking, writeable)` still works but is deprecated,
+//! use [`FileLock::lock_shared`](struct.FileLock.html#method.lock_shared),
+//! [`FileLock::lock_exclusive`](struct.FileLock.html#method.lock_exclusive)
+//! or the builder returned by [`FileLock::new`](struct.FileLock.html#method.new)
+//! instead.
 
 use nix::{
-    fcntl::{fcntl, FcntlArg},
+    errno::Errno,
+    fcntl::{fcntl, openat, FdFlag, FlockArg, OFlag},
     libc,
+    sys::stat::Mode,
 };
 use std::{
+    fmt,
     fs::{File, OpenOptions},
-    io::{prelude::*, Error, ErrorKind, IoSlice, IoSliceMut, Result, SeekFrom},
+    io::{
+        prelude::*, BufReader, BufWriter, Error, ErrorKind, IoSlice,
+        IoSliceMut, Result, SeekFrom,
+    },
+    ops::{Deref, DerefMut},
     os::unix::{
-        fs::FileExt,
-        io::{AsRawFd, RawFd},
+        fs::{FileExt, OpenOptionsExt},
+        io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
     },
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
 };
 
+#[cfg(feature = "async")]
+mod async_lock;
+#[cfg(feature = "events")]
+mod events;
+mod holder;
+mod interrupt;
+#[cfg(feature = "leak-check")]
+mod leak;
+mod link;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod pidfile;
+mod ranges;
+mod replace;
+mod rwlock;
+
+#[cfg(feature = "async")]
+pub use async_lock::{AsyncFileLock, LockFuture};
+#[cfg(feature = "events")]
+pub use events::{LockEvent, LockEventKind};
+pub use holder::HolderInfo;
+pub use interrupt::CancelToken;
+#[cfg(feature = "leak-check")]
+pub use leak::LeakRecord;
+#[cfg(feature = "mmap")]
+pub use mmap::{LockedMap, LockedMapMut};
+pub use nix::fcntl::FcntlArg;
+pub use pidfile::{PidFile, SingleInstance};
+pub use replace::Replacement;
+pub use rwlock::{FileRwLock, ReadGuard, WriteGuard};
+
+use ranges::RangeMap;
+
+/// Cap every blocking lock acquisition of the process at `max_wait`
+///
+/// Once set, blocking locks wait on a helper thread that is interrupted
+/// after `max_wait`, the same way as
+/// [`FileLock::lock_timeout_thread`](struct.FileLock.html#method.lock_timeout_thread),
+/// and fail with an error of kind `ErrorKind::TimedOut` instead of hanging.
+/// A timeout given to a call overrides this default. `None`, the initial
+/// value, lets blocking locks wait forever.
+///
+/// # Examples
+///
+///```
+///use std::time::Duration;
+///
+///file_locker::set_default_max_wait(Some(Duration::from_secs(30)));
+///```
+///
+pub fn set_default_max_wait(max_wait: Option<Duration>) {
+    interrupt::set_default_max_wait(max_wait);
+}
+
+/// Check whether the filesystem holding `dir` supports advisory record locks
+///
+/// A temporary file is created in `dir`, write locked with `F_SETLK`, and
+/// removed again. On Linux it is created with `O_TMPFILE`, so it never has a
+/// name; elsewhere, or if the filesystem doesn't support `O_TMPFILE`, it is
+/// unlinked right after being created. Returns false if the filesystem
+/// refuses the lock (`ENOLCK`, `EOPNOTSUPP`), and an error if the file can't
+/// be created, e.g. because `dir` isn't writeable.
+///
+/// This only tells whether locks are accepted, see
+/// [`FileLock::verify_enforcement`](struct.FileLock.html#method.verify_enforcement)
+/// to check that they are enforced.
+///
+/// # Examples
+///
+///```
+///use std::io::Result;
+///use std::path::Path;
+///
+///fn main() -> Result<()> {
+///    if !file_locker::filesystem_supports_locks(Path::new("."))? {
+///        eprintln!("warning: no file locking in the data directory");
+///    }
+///    Ok(())
+///}
+///```
+///
+pub fn filesystem_supports_locks(dir: &Path) -> Result<bool> {
+    let file = probe_file(dir)?;
+    let flock = new_flock(libc::F_WRLCK, 0, 0);
+    match fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&flock)) {
+        Ok(_) => Ok(true),
+        Err(Errno::ENOLCK) | Err(Errno::EOPNOTSUPP) | Err(Errno::ENOSYS) => {
+            Ok(false)
+        }
+        Err(e) => Err(cver(e)),
+    }
+}
+
+/// Create a nameless file in `dir`
+fn probe_file(dir: &Path) -> Result<File> {
+    use std::sync::atomic::AtomicUsize;
+
+    #[cfg(target_os = "linux")]
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_TMPFILE)
+        .mode(0o600)
+        .open(dir)
+    {
+        Ok(file) => return Ok(file),
+        // O_TMPFILE not supported by the filesystem or kernel
+        Err(e)
+            if e.raw_os_error() == Some(libc::EOPNOTSUPP)
+                || e.raw_os_error() == Some(libc::EISDIR) => {}
+        Err(e) => return Err(e),
+    }
+
+    static PROBES: AtomicUsize = AtomicUsize::new(0);
+    let path = dir.join(format!(
+        ".file-locker-probe-{}-{}",
+        std::process::id(),
+        PROBES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Send an event for every lock change to `sink`
+///
+/// Each [`LockEvent`](struct.LockEvent.html) gives the file, the range and
+/// when it happened. A blocking request that has to wait is probed with
+/// `F_SETLK` first to report `LockEventKind::Blocked`. `Sender::send` never
+/// blocks, so a slow receiver can't stall a lock operation; events are
+/// dropped, and the sink removed, once the receiver hangs up. Setting a new
+/// sink replaces the previous one.
+///
+/// # Examples
+///
+///```
+///use file_locker::FileLock;
+///use std::io::Result;
+///use std::sync::mpsc;
+///
+///fn main() -> Result<()> {
+///    let (tx, rx) = mpsc::channel();
+///    file_locker::set_event_sink(tx);
+///    let filelock = FileLock::lock_shared("myfile.txt")?;
+///    for event in rx.try_iter() {
+///        println!("{:?}", event);
+///    }
+///    Ok(())
+///}
+///```
+///
+#[cfg(feature = "events")]
+pub fn set_event_sink(sink: std::sync::mpsc::Sender<LockEvent>) {
+    events::set_sink(Some(sink));
+}
+
+/// Stop sending events set up with
+/// [`set_event_sink`](fn.set_event_sink.html)
+#[cfg(feature = "events")]
+pub fn clear_event_sink() {
+    events::set_sink(None);
+}
+
+/// Every `FileLock` of the process that currently holds a lock
+///
+/// Handles are registered when they first lock something and removed once
+/// they hold nothing, are dropped, or are turned back into a `File`. Each
+/// record carries the backtrace of the first acquisition, so test teardown
+/// can assert nothing was left held and show where it came from.
+///
+/// # Examples
+///
+///```
+///fn teardown() {
+///    let leaked = file_locker::leaked_locks();
+///    assert!(leaked.is_empty(), "locks left held: {:#?}", leaked);
+///}
+///```
+///
+#[cfg(feature = "leak-check")]
+pub fn leaked_locks() -> Vec<LeakRecord> {
+    leak::leaked_locks()
+}
+
 /// Represents the actually locked file
+///
+/// Record locks belong to the process and don't depend on its credentials:
+/// only the open file's access mode matters (a write lock needs a writeable
+/// file). A setuid helper can therefore open and lock a file as root, drop
+/// privileges, and keep holding, changing or releasing the lock through the
+/// same handle even if the new user couldn't open the file itself.
+///
+/// Dropping a `FileLock` releases the lock and closes the file. To keep the
+/// file open after the lock is gone, lock a `File` you own with
+/// [`FileLock::lock_fd`](#method.lock_fd): the returned
+/// [`FdLock`](struct.FdLock.html) borrows the file and only represents the
+/// lock.
 #[derive(Debug)]
 pub struct FileLock {
-    /// the `std::fs::File` of the file that's locked
-    pub file: File,
+    // fields other than `file` must be dropped in `into_file`
+    file: File,
+    held: Mutex<RangeMap>,
+    // mirrors `!held.is_empty()` so it can be read without locking
+    locked: AtomicBool,
+    backend: Backend,
+    on_drop: DropAction,
+}
+
+/// What dropping a `FileLock` does, see `OnDrop`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum DropAction {
+    Unlock,
+    Delete(PathBuf),
+    Close,
+}
+
+/// How long a lock change may wait for conflicting locks to go away
+#[derive(Debug, Clone)]
+enum Block {
+    No,
+    /// Unless capped by `set_default_max_wait()`, what to do about `EINTR`
+    /// otherwise
+    Forever(OnInterrupt),
+    For(Duration),
+    /// Until the token is cancelled, and for at most the duration if any,
+    /// or else the default maximum wait
+    Cancellable(CancelToken, Option<Duration>),
+}
+
+impl From<bool> for Block {
+    fn from(blocking: bool) -> Block {
+        if blocking {
+            Block::Forever(OnInterrupt::Return)
+        } else {
+            Block::No
+        }
+    }
+}
+
+/// How lock changes are applied to the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Backend {
+    /// POSIX record locks via `fcntl()`
+    Fcntl,
+    /// Open file description locks via `fcntl()`
+    #[cfg(target_os = "linux")]
+    Ofd,
+    /// BSD whole-file locks via `flock()`
+    Flock,
+    /// A lock file linked into place next to the file
+    Link(link::LinkLock),
+    /// Nothing is locked, only the handle's bookkeeping is updated
+    Noop,
 }
 
 impl FileLock {
@@ -68,19 +363,78 @@ impl FileLock {
     ///                     .blocking(true)
     ///                     .lock()?;
     ///
-    ///    filelock.file.write_all(b"Hello, world")?;
+    ///    filelock.write_all(b"Hello, world")?;
     ///    Ok(())
     ///}
     ///```
     ///
+    #[allow(clippy::new_ret_no_self)]
     pub fn new<T: AsRef<Path>>(file_path: T) -> FileLockBuilder<T> {
         FileLockBuilder {
             file_path,
             blocking: false,
             writeable: false,
+            range: (0, 0),
+            validate: None,
+            enabled: true,
+            ofd: false,
+            flock: false,
+            link: false,
+            retries: 0,
+            backoff: Backoff::default(),
+            cancel: None,
+            on_interrupt: OnInterrupt::default(),
+            on_drop: OnDrop::default(),
+            holder_label: None,
+            open_options: None,
+            append: false,
+            create_new: false,
+            truncate: false,
+            mode: None,
+            create: None,
+            exclusive: None,
+            cloexec: true,
+            dir: None,
         }
     }
 
+    /// Create a [`FileLockBuilder`](struct.FileLockBuilder.html) for
+    /// `file_path` relative to the directory `dir`, opening it with
+    /// `openat()`
+    ///
+    /// The file is always looked up in `dir`, even if `file_path` is
+    /// absolute, which suits programs that can only reach files through
+    /// directories they were handed. As the file has no path of its own,
+    /// [`open_options`](struct.FileLockBuilder.html#method.open_options),
+    /// [`link_lockfile`](struct.FileLockBuilder.html#method.link_lockfile)
+    /// and [`OnDrop::Delete`](enum.OnDrop.html#variant.Delete) fail with
+    /// `ErrorKind::InvalidInput`.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::fs::File;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let dir = File::open(".")?;
+    ///    let filelock = FileLock::new_at(&dir, "state.lock")
+    ///                     .writeable(true)
+    ///                     .lock()?;
+    ///    # let _ = std::fs::remove_file("state.lock");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn new_at<T: AsRef<Path>>(
+        dir: impl AsFd,
+        file_path: T,
+    ) -> FileLockBuilder<T> {
+        let mut builder = FileLock::new(file_path);
+        builder.dir = Some(Arc::new(dir.as_fd().try_clone_to_owned()));
+        builder
+    }
+
     /// Try to lock the specified file
     ///
     /// # Parameters
@@ -97,6 +451,7 @@ impl FileLock {
     /// # Examples
     ///
     ///```
+    ///# #![allow(deprecated)]
     ///use file_locker::FileLock;
     ///use std::io::prelude::*;
     ///use std::io::Result;
@@ -105,319 +460,7134 @@ impl FileLock {
     ///    let mut filelock = FileLock::lock("myfile.txt", false, false)?;
     ///
     ///    let mut buf = String::new();
-    ///    filelock.file.read_to_string(&mut buf)?;
+    ///    filelock.read_to_string(&mut buf)?;
     ///    Ok(())
     ///}
     ///```
     ///
+    #[deprecated(
+        since = "2.0.0",
+        note = "use `FileLock::lock_shared`, `FileLock::lock_exclusive` or \
+                `FileLock::new` instead of boolean flags"
+    )]
     pub fn lock(
         file_path: impl AsRef<Path>,
         blocking: bool,
         writeable: bool,
     ) -> Result<FileLock> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(writeable)
-            .create(writeable)
-            .open(&file_path)?;
-        let flock = libc::flock {
-            l_type: if writeable {
-                libc::F_WRLCK
-            } else {
-                libc::F_RDLCK
-            } as i16,
-            l_whence: libc::SEEK_SET as i16,
-            l_start: 0,
-            l_len: 0,
-            l_pid: 0,
-            #[cfg(target_os = "freebsd")]
-            l_sysid: 0,
-        };
-        let arg = if blocking {
-            FcntlArg::F_SETLKW(&flock)
-        } else {
-            FcntlArg::F_SETLK(&flock)
-        };
-        fcntl(file.as_raw_fd(), arg).map_err(cver)?;
-        Ok(Self { file })
+        FileLock::new(file_path)
+            .blocking(blocking)
+            .writeable(writeable)
+            .lock()
     }
 
-    /// Unlock our locked file
+    /// Lock a file that was opened by the caller
     ///
-    /// *Note:* This method is optional as the file lock will be unlocked automatically when dropped
+    /// This is for files that need `OpenOptions` this crate doesn't use,
+    /// such as `append` or a custom mode. The whole file is locked, for
+    /// writing if `writeable` (which requires `file` to be open for writing),
+    /// for reading otherwise. On failure `file` is closed, which releases any
+    /// other lock this process holds on it.
     ///
     /// # Examples
     ///
     ///```
     ///use file_locker::FileLock;
+    ///use std::fs::OpenOptions;
     ///use std::io::prelude::*;
     ///use std::io::Result;
     ///
     ///fn main() -> Result<()> {
-    ///    let mut filelock = FileLock::new("myfile.txt")
-    ///                     .writeable(true)
-    ///                     .blocking(true)
-    ///                     .lock()?;
-    ///
-    ///    filelock.file.write_all(b"Hello, world")?;
-    ///
-    ///    filelock.unlock()?;
+    ///    let file = OpenOptions::new()
+    ///        .append(true)
+    ///        .create(true)
+    ///        .open("myfile.txt")?;
+    ///    let mut filelock = FileLock::from_file(file, true, true)?;
+    ///    filelock.write_all(b"appended\n")?;
     ///    Ok(())
     ///}
     ///```
     ///
-    pub fn unlock(&self) -> Result<()> {
-        let flock = libc::flock {
-            l_type: libc::F_UNLCK as i16,
-            l_whence: libc::SEEK_SET as i16,
-            l_start: 0,
-            l_len: 0,
-            l_pid: 0,
-            #[cfg(target_os = "freebsd")]
-            l_sysid: 0,
-        };
-        fcntl(self.file.as_raw_fd(), FcntlArg::F_SETLK(&flock))
-            .map_err(cver)?;
-        Ok(())
+    pub fn from_file(
+        file: File,
+        blocking: bool,
+        writeable: bool,
+    ) -> Result<FileLock> {
+        let filelock = FileLock::unlocked(file);
+        filelock.lock_range(0, 0, blocking, writeable)?;
+        Ok(filelock)
     }
-}
 
-impl Read for FileLock {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.file.read(buf)
+    /// Lock the file behind a descriptor the caller keeps owning
+    ///
+    /// This works with anything implementing `AsFd`, e.g. a `File` received
+    /// over a Unix socket or a memfd. The whole file is locked, for writing
+    /// if `writeable` (which requires the descriptor to be open for writing).
+    /// The returned [`FdLock`](struct.FdLock.html) borrows the descriptor
+    /// and releases the lock when dropped, but never closes it.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::fs::File;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let file = File::create("myfile.txt")?;
+    ///    let filelock = FileLock::lock_fd(&file, true, true)?;
+    ///    filelock.file().set_len(0)?;
+    ///    drop(filelock);
+    ///    // file is still open
+    ///    file.sync_all()?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_fd<F: AsFd>(
+        fd: &F,
+        blocking: bool,
+        writeable: bool,
+    ) -> Result<FdLock<'_>> {
+        let fdlock = FdLock::new(fd.as_fd());
+        fdlock.lock_range(0, 0, blocking, writeable)?;
+        Ok(fdlock)
     }
 
-    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
-        self.file.read_vectored(bufs)
+    /// Lock the whole file for reading, waiting until no writer holds it
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::lock_shared("myfile.txt")?;
+    ///
+    ///    let mut buf = String::new();
+    ///    filelock.read_to_string(&mut buf)?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_shared(file_path: impl AsRef<Path>) -> Result<FileLock> {
+        FileLock::new(file_path).blocking(true).lock()
     }
-}
 
-impl Write for FileLock {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.file.write(buf)
+    /// Open the file as writeable, creating it if needed, but lock it for
+    /// reading only, waiting until no writer holds it
+    ///
+    /// POSIX only requires a file open for reading to take a read lock, so
+    /// a read lock on a writeable file is fine and coexists with other
+    /// readers. The point is that the handle can later
+    /// [`upgrade`](#method.upgrade) to a write lock in place, which a
+    /// read-only handle can't do without reopening the file (and reopening
+    /// then closing it would release the read lock, see
+    /// [`backend_drops_on_any_close`](#method.backend_drops_on_any_close)).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::lock_shared_rw("myfile.txt")?;
+    ///
+    ///    let mut buf = String::new();
+    ///    filelock.read_to_string(&mut buf)?;
+    ///    if buf.is_empty() {
+    ///        filelock.upgrade(true)?;
+    ///        filelock.write_all(b"Hello, world")?;
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_shared_rw(file_path: impl AsRef<Path>) -> Result<FileLock> {
+        let file = open(file_path, true)?;
+        let filelock = FileLock::unlocked(file);
+        filelock.lock_range(0, 0, true, false)?;
+        Ok(filelock)
     }
 
-    fn flush(&mut self) -> Result<()> {
-        self.file.flush()
+    /// Open the file as writeable, creating it if needed, and lock it
+    /// exclusively, waiting until nobody else holds it
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::lock_exclusive("myfile.txt")?;
+    ///
+    ///    filelock.write_all(b"Hello, world")?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_exclusive(file_path: impl AsRef<Path>) -> Result<FileLock> {
+        FileLock::new(file_path)
+            .blocking(true)
+            .writeable(true)
+            .lock()
     }
 
-    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
-        self.file.write_vectored(bufs)
+    /// Lock the whole file, waiting at most `timeout` for it to be released
+    ///
+    /// The blocking `F_SETLKW` runs on a helper thread. Once `timeout` has
+    /// passed, that thread is sent `SIGURG` to interrupt the wait, and an
+    /// error of kind `ErrorKind::TimedOut` is returned. The helper thread is
+    /// always joined before returning, and the file is closed on failure.
+    ///
+    /// A no-op `SIGURG` handler is installed on first use, unless the
+    /// application already handles `SIGURG`, in which case its handler must
+    /// be installed without `SA_RESTART`.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::time::Duration;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::lock_timeout_thread(
+    ///        "myfile.txt",
+    ///        true,
+    ///        Duration::from_secs(1),
+    ///    )?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_timeout_thread(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+        timeout: Duration,
+    ) -> Result<FileLock> {
+        let file = open(file_path, writeable)?;
+        let filelock = FileLock::unlocked(file);
+        filelock.lock_whole_timeout(writeable, timeout)?;
+        Ok(filelock)
     }
-}
 
-impl Seek for FileLock {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        self.file.seek(pos)
+    /// Like [`lock_timeout_thread`](#method.lock_timeout_thread), but
+    /// describing how the wait went on failure
+    ///
+    /// The returned [`TimeoutInfo`](struct.TimeoutInfo.html) tells how long
+    /// was waited, how much of `timeout` is left, and whether the file was
+    /// still held by someone else (`contended`) or the attempt failed for
+    /// another reason, e.g. the file couldn't be opened. Callers running
+    /// their own retry loop can use it to adjust their strategy.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::time::Duration;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = match FileLock::lock_timeout_detailed(
+    ///        "myfile.txt",
+    ///        true,
+    ///        Duration::from_secs(1),
+    ///    ) {
+    ///        Ok(filelock) => filelock,
+    ///        Err(info) if info.contended => {
+    ///            eprintln!("still busy after {:?}", info.waited);
+    ///            return Err(info.into());
+    ///        }
+    ///        Err(info) => return Err(info.into()),
+    ///    };
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_timeout_detailed(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+        timeout: Duration,
+    ) -> std::result::Result<FileLock, TimeoutInfo> {
+        let started = std::time::Instant::now();
+        let info = |error, contended| {
+            let waited = started.elapsed();
+            TimeoutInfo {
+                waited,
+                remaining: timeout.saturating_sub(waited),
+                contended,
+                error,
+            }
+        };
+        let file = open(file_path, writeable).map_err(|e| info(e, false))?;
+        let filelock = FileLock::unlocked(file);
+        match filelock.lock_whole_timeout(writeable, timeout) {
+            Ok(()) => Ok(filelock),
+            Err(error) => {
+                let lock_type = if writeable {
+                    LockType::Write
+                } else {
+                    LockType::Read
+                };
+                let contended = matches!(
+                    conflicting_lock(&filelock.file, lock_type),
+                    Ok(Some(_))
+                );
+                Err(info(error, contended))
+            }
+        }
     }
-}
 
-impl AsRawFd for FileLock {
-    fn as_raw_fd(&self) -> RawFd {
-        self.file.as_raw_fd()
+    /// Lock several byte ranges of a file, either all of them or none
+    ///
+    /// Each `(start, len)` range is locked without blocking, in order. If
+    /// one of them is held by another process, the ranges acquired so far
+    /// are released and an error of kind `ErrorKind::WouldBlock` naming the
+    /// contended range is returned.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::lock_ranges_nonblocking(
+    ///        "myfile.txt",
+    ///        true,
+    ///        &[(0, 64), (512, 64)],
+    ///    )?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_ranges_nonblocking(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+        ranges: &[(u64, u64)],
+    ) -> Result<FileLock> {
+        FileLock::lock_ranges(file_path, false, writeable, ranges)
     }
-}
 
-impl FileExt for FileLock {
-    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
-        self.file.read_at(buf, offset)
+    /// Lock several byte ranges of a file, either all of them or none
+    ///
+    /// Each `(start, len)` range is locked in order. If locking one of them
+    /// fails, including when the kernel detects a deadlock (`EDEADLK`) while
+    /// waiting for it, the ranges acquired so far are released before the
+    /// error is returned, and the error message names the failing range.
+    ///
+    /// To reduce the risk of deadlocks, processes sharing a file should lock
+    /// its ranges in the same order.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock =
+    ///        FileLock::lock_ranges("myfile.txt", true, true, &[(0, 64), (512, 64)])?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_ranges(
+        file_path: impl AsRef<Path>,
+        blocking: bool,
+        writeable: bool,
+        ranges: &[(u64, u64)],
+    ) -> Result<FileLock> {
+        let file = open(file_path, writeable)?;
+        let filelock = FileLock::unlocked(file);
+        filelock.lock_all(ranges, blocking, writeable)?;
+        Ok(filelock)
     }
 
-    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
-        self.file.write_at(buf, offset)
+    /// Lock several whole files (blocking), in an order every process
+    /// agrees on
+    ///
+    /// All files are opened first, then locked by ascending device and inode
+    /// number, so two processes locking overlapping sets of files can't
+    /// deadlock whatever order they list them in. The locks are returned in
+    /// the order of `paths`. If one fails, those acquired so far are released
+    /// when they are dropped, before the error is returned.
+    ///
+    /// A file must only be listed once: two handles on it would share the
+    /// process' lock, and dropping one would release it for both.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let locks = FileLock::lock_many(&["a.txt", "b.txt"], true)?;
+    ///    # let _ = std::fs::remove_file("a.txt");
+    ///    # let _ = std::fs::remove_file("b.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_many<P: AsRef<Path>>(
+        paths: &[P],
+        writeable: bool,
+    ) -> Result<Vec<FileLock>> {
+        use std::os::unix::fs::MetadataExt;
+
+        FileLock::lock_many_with(paths, writeable, |_, file| {
+            let metadata = file.metadata()?;
+            Ok((metadata.dev(), metadata.ino()))
+        })
+    }
+
+    /// Like [`lock_many`](#method.lock_many), but in ascending order of
+    /// `key_fn(path)` instead of inode number
+    ///
+    /// This is for applications with their own canonical ordering of
+    /// resources, e.g. by logical name, when the same resource may be
+    /// reached through different inodes on different nodes. Paths with equal
+    /// keys are locked in the order they are listed.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let locks = FileLock::lock_many_by_key(
+    ///        &["b.txt", "a.txt"],
+    ///        true,
+    ///        |path| path.file_name().map(|name| name.to_owned()),
+    ///    )?;
+    ///    # let _ = std::fs::remove_file("a.txt");
+    ///    # let _ = std::fs::remove_file("b.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_many_by_key<P, K, F>(
+        paths: &[P],
+        writeable: bool,
+        key_fn: F,
+    ) -> Result<Vec<FileLock>>
+    where
+        P: AsRef<Path>,
+        K: Ord,
+        F: Fn(&Path) -> K,
+    {
+        FileLock::lock_many_with(paths, writeable, |path, _| Ok(key_fn(path)))
+    }
+
+    /// Lock only the data regions of a sparse file, skipping its holes
+    ///
+    /// The data extents are found with `lseek()`'s `SEEK_DATA` and
+    /// `SEEK_HOLE`, and each one is then locked like
+    /// [`lock_ranges_nonblocking`](#method.lock_ranges_nonblocking) does.
+    /// Extents allocated after this call are not covered. On filesystems
+    /// which don't track holes, the whole file is a single data region.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::lock_data_regions("myfile.txt", false)?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    pub fn lock_data_regions(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+    ) -> Result<FileLock> {
+        let file = open(file_path, writeable)?;
+        let filelock = FileLock::unlocked(file);
+        let regions = data_regions(&filelock.file)?;
+        filelock.lock_all(&regions, false, writeable)?;
+        Ok(filelock)
+    }
+
+    /// Exclusively lock the region past the end of the file, for appending
+    ///
+    /// The file is opened as writeable (created if needed) and write locked
+    /// from its current end onwards, using an `SEEK_END` relative lock, so
+    /// that bytes appended later are covered while existing bytes remain
+    /// available to be locked by readers. This blocks until the tail is free.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::{Result, SeekFrom};
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::lock_growing("myfile.txt")?;
+    ///    filelock.seek(SeekFrom::End(0))?;
+    ///    filelock.write_all(b"new record\n")?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_growing(file_path: impl AsRef<Path>) -> Result<FileLock> {
+        let file = open(file_path, true)?;
+        let filelock = FileLock::unlocked(file);
+        let flock = build_flock(LockType::Write, libc::SEEK_END, 0, 0)?;
+
+        let mut held = filelock.held();
+        interrupt::setlkw(
+            &filelock.file,
+            &flock,
+            interrupt::Wait::Setlkw,
+            OnInterrupt::Return,
+        )?;
+        // nobody else can append while we hold the tail, so the current
+        // length is where the lock starts
+        let start = filelock.file.metadata()?.len();
+        filelock.record(&mut held, start, None, LockType::Write);
+        drop(held);
+        Ok(filelock)
+    }
+
+    /// Lock the whole file (blocking) and return a fence token identifying
+    /// the file that was locked
+    ///
+    /// The token combines the device and inode numbers of the locked file,
+    /// read through the open handle once the lock is held. If the file is
+    /// unlinked or renamed over and recreated, locking the path again yields
+    /// a different token (as long as the old inode hasn't been freed and
+    /// reused), so a caller that stored the token can tell the resource it
+    /// locked before is gone.
+    ///
+    /// The token only identifies the file, not its contents. For full
+    /// fencing, pair it with a generation counter kept in the file and bumped
+    /// under the lock, e.g. with
+    /// [`increment_counter`](#method.increment_counter): the resource is
+    /// unchanged only if both the token and the generation match.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let (filelock, fence) = FileLock::lock_with_fence("myfile.txt", true)?;
+    ///    println!("holding {:x}", fence);
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_with_fence(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+    ) -> Result<(FileLock, u64)> {
+        use std::os::unix::fs::MetadataExt;
+
+        let filelock = FileLock::new(file_path)
+            .blocking(true)
+            .writeable(writeable)
+            .lock()?;
+        let metadata = filelock.file.metadata()?;
+        let fence = metadata.dev().rotate_left(32) ^ metadata.ino();
+        Ok((filelock, fence))
+    }
+
+    /// Unlock our locked file
+    ///
+    /// *Note:* This method is optional as the file lock will be unlocked automatically when dropped
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .blocking(true)
+    ///                     .lock()?;
+    ///
+    ///    filelock.write_all(b"Hello, world")?;
+    ///
+    ///    filelock.unlock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn unlock(&self) -> Result<()> {
+        self.set_lock(LockType::Unlocked, 0, None, false)
+    }
+
+    /// The `std::fs::File` of the file that's locked
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Mutable access to the `std::fs::File` of the file that's locked
+    ///
+    /// *Note:* replacing or closing this `File` releases the lock.
+    pub fn file_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Release the lock and give back the `File` for unlocked use
+    ///
+    /// The file stays open, keeping its current position.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .blocking(true)
+    ///                     .lock()?;
+    ///
+    ///    filelock.write_all(b"Hello, world")?;
+    ///
+    ///    let mut file = filelock.into_inner()?;
+    ///    file.write_all(b", unlocked")?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn into_inner(self) -> Result<File> {
+        self.unlock()?;
+        Ok(self.into_file())
+    }
+
+    /// Read the description written into the file by
+    /// [`FileLockBuilder::record_holder`](struct.FileLockBuilder.html#method.record_holder),
+    /// `None` if it doesn't hold one
+    ///
+    /// Unlike [`HolderInfo::read`](struct.HolderInfo.html#method.read), this
+    /// reads through this handle, so it doesn't affect the locks of this
+    /// process.
+    pub fn holder_info(&self) -> Result<Option<HolderInfo>> {
+        let mut contents = vec![0; self.file.metadata()?.len() as usize];
+        self.file.read_exact_at(&mut contents, 0)?;
+        Ok(HolderInfo::parse(&contents))
+    }
+
+    /// Exclusively lock a file, creating it if needed, to replace its
+    /// contents atomically
+    ///
+    /// The new contents are written to the returned
+    /// [`Replacement`](struct.Replacement.html) and only take the place of
+    /// the old ones on [`commit`](struct.Replacement.html#method.commit),
+    /// with a rename. Readers can therefore read the file without locking
+    /// it, while writers exclude each other by going through this function,
+    /// which makes sure the file it locked wasn't renamed over while it
+    /// waited.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut replacement = FileLock::replace("config.txt")?;
+    ///    replacement.write_all(b"key = value\n")?;
+    ///    replacement.commit()?;
+    ///    # let _ = std::fs::remove_file("config.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn replace(file_path: impl AsRef<Path>) -> Result<Replacement> {
+        Replacement::new(file_path.as_ref())
+    }
+
+    /// Give back the `File` without releasing the lock
+    ///
+    /// The lock is no longer tied to this handle and stays held until the
+    /// `File` is closed, or, for record locks, until the process closes any
+    /// descriptor of the file or exits. This suits a daemon that keeps its
+    /// lock for its whole life, e.g. across daemonizing, without resorting
+    /// to `mem::forget`. Use `into_raw_fd()` on the `File` to keep it open
+    /// indefinitely.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::os::unix::io::IntoRawFd;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::lock_exclusive("myfile.txt")?;
+    ///    let fd = filelock.persist_lock().into_raw_fd();
+    ///    // the lock is held until the process exits
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn persist_lock(self) -> File {
+        self.into_file()
+    }
+
+    /// Lock an additional byte range of the file
+    ///
+    /// A `len` of 0 locks up to the end of the file, however far it grows.
+    /// Any part of the range already held by this handle is converted to the
+    /// requested lock type. A write lock requires the file to have been opened
+    /// as writeable.
+    ///
+    /// The rest of what the handle holds is left alone, and the held ranges
+    /// are split and merged the same way the kernel does. For instance, with
+    /// bytes 0..100 read locked:
+    ///
+    /// - a write lock on 40..60 leaves 0..40 read, 40..60 write and 60..100
+    ///   read locked
+    /// - a write lock on 50..150 leaves 0..50 read and 50..150 write locked
+    /// - a read lock on 100..150 leaves 0..150 read locked
+    /// - a write lock from 0 with a `len` of 0 leaves the whole file write
+    ///   locked
+    ///
+    /// If the new lock can't be taken, what was held before is unchanged.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .range(0, 16)
+    ///                     .lock()?;
+    ///
+    ///    filelock.lock_range(64, 16, true, true)?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_range(
+        &self,
+        start: u64,
+        len: u64,
+        blocking: bool,
+        writeable: bool,
+    ) -> Result<()> {
+        let lock_type = if writeable {
+            LockType::Write
+        } else {
+            LockType::Read
+        };
+        self.set_lock(lock_type, start, range_end(start, len)?, blocking)
+    }
+
+    /// Release a byte range of the file, keeping the rest locked
+    ///
+    /// A `len` of 0 releases everything from `start` onwards.
+    pub fn unlock_range(&self, start: u64, len: u64) -> Result<()> {
+        self.set_lock(LockType::Unlocked, start, range_end(start, len)?, false)
+    }
+
+    /// Grow the locked region by `additional_len` bytes past its end
+    ///
+    /// The new bytes get the same kind of lock as the last held range. This
+    /// doesn't block: if another process holds part of the new bytes, an
+    /// error of kind `ErrorKind::WouldBlock` is returned and nothing changes.
+    /// Fails with `ErrorKind::InvalidInput` if nothing is held or the region
+    /// already extends to the end of the file.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .range(0, 64)
+    ///                     .lock()?;
+    ///
+    ///    // now holding bytes 0..128
+    ///    filelock.extend(64)?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn extend(&self, additional_len: u64) -> Result<()> {
+        let last = self.held().iter().last().copied();
+        let last = last.ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "no lock is held")
+        })?;
+        let end = last.end.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "lock already extends to the end of the file",
+            )
+        })?;
+        if additional_len == 0 {
+            return Ok(());
+        }
+        self.set_lock(
+            last.lock_type,
+            end,
+            range_end(end, additional_len)?,
+            false,
+        )
+    }
+
+    /// Release everything past the first `new_len` bytes of the locked
+    /// region
+    ///
+    /// The region starts at the first byte held by this handle. A `new_len`
+    /// of 0 releases the whole region, and shrinking to a length past its end
+    /// changes nothing.
+    pub fn shrink(&self, new_len: u64) -> Result<()> {
+        let first = self.held().iter().next().copied();
+        match first {
+            Some(first) => {
+                let start =
+                    first.start.checked_add(new_len).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "length overflows")
+                    })?;
+                self.unlock_range(start, 0)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Whether this handle currently holds a lock on any part of the file
+    ///
+    /// This reflects what was locked and released through this handle, and
+    /// doesn't ask the kernel. It is false after
+    /// [`unlock`](#method.unlock) or once every range has been released, in
+    /// which case dropping the handle has nothing left to release.
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::SeqCst)
+    }
+
+    /// Whether closing any descriptor of the file in this process, not just
+    /// this handle's, releases this handle's locks
+    ///
+    /// This is the case for classic POSIX record locks, which belong to the
+    /// process rather than to the open file: a library opening and closing
+    /// the same file behind your back silently drops the lock. Open file
+    /// description locks, see
+    /// [`FileLockBuilder::ofd`](struct.FileLockBuilder.html#method.ofd), and
+    /// `flock()` locks, see
+    /// [`FileLockBuilder::flock`](struct.FileLockBuilder.html#method.flock),
+    /// don't have this problem, and handles made with
+    /// [`FileLockBuilder::enabled(false)`](struct.FileLockBuilder.html#method.enabled)
+    /// hold no lock; all of those return false.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::lock_shared("myfile.txt")?;
+    ///    if filelock.backend_drops_on_any_close() {
+    ///        // don't open myfile.txt anywhere else while holding the lock
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn backend_drops_on_any_close(&self) -> bool {
+        match self.backend {
+            Backend::Fcntl => true,
+            #[cfg(target_os = "linux")]
+            Backend::Ofd => false,
+            Backend::Flock | Backend::Link(_) | Backend::Noop => false,
+        }
+    }
+
+    /// Another handle to the locked file, e.g. for a second worker thread
+    ///
+    /// The clone has a new descriptor of the same open file, made with
+    /// `File::try_clone`, and holds no ranges of its own: dropping it never
+    /// unlocks anything. What locking or unlocking through it does depends
+    /// on the backend:
+    ///
+    /// - [`ofd`](struct.FileLockBuilder.html#method.ofd) and
+    ///   [`flock`](struct.FileLockBuilder.html#method.flock) locks belong to
+    ///   the open file both handles share, so the clone changes the same
+    ///   locks as this handle
+    /// - [`link_lockfile`](struct.FileLockBuilder.html#method.link_lockfile)
+    ///   locks belong to the lock file, which both handles hold under the
+    ///   same id
+    /// - the default `fcntl()` locks belong to the process, and closing the
+    ///   clone's descriptor would release all of them, see
+    ///   [`backend_drops_on_any_close`](#method.backend_drops_on_any_close).
+    ///   This fails with `ErrorKind::Unsupported` instead.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::os::unix::fs::FileExt;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .flock(true)
+    ///                     .lock()?;
+    ///    let worker = filelock.try_clone()?;
+    ///    std::thread::spawn(move || worker.write_at(b"from worker", 0))
+    ///        .join()
+    ///        .unwrap()?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn try_clone(&self) -> Result<FileLock> {
+        if let Backend::Fcntl = self.backend {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "closing a clone would release the process' fcntl() locks",
+            ));
+        }
+        let mut filelock = FileLock::unlocked(self.file.try_clone()?);
+        filelock.backend = self.backend.clone();
+        Ok(filelock)
+    }
+
+    /// Turn every read lock held by this handle into a write lock
+    ///
+    /// The file must be open for writing, e.g. with
+    /// [`lock_shared_rw`](#method.lock_shared_rw). If `blocking`, this waits
+    /// for other readers to leave; two handles upgrading at once would wait
+    /// on each other, which the kernel reports as an error of kind
+    /// `ErrorKind::Deadlock` to one of them. Otherwise other readers make
+    /// it fail with `ErrorKind::WouldBlock`. Either way, ranges upgraded
+    /// before the failure stay write locked. A handle opened read-only, e.g.
+    /// by [`lock_shared`](#method.lock_shared), fails with
+    /// `ErrorKind::PermissionDenied` without changing anything.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///use std::os::unix::fs::FileExt;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::lock_shared_rw("flag.txt")?;
+    ///    let mut state = String::new();
+    ///    filelock.read_to_string(&mut state)?;
+    ///    if state != "done" {
+    ///        // no other process can change the file in between
+    ///        filelock.upgrade(true)?;
+    ///        filelock.set_len(0)?;
+    ///        filelock.write_all_at(b"done", 0)?;
+    ///    }
+    ///    # let _ = std::fs::remove_file("flag.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn upgrade(&self, blocking: bool) -> Result<()> {
+        let flags =
+            fcntl(self.file.as_raw_fd(), FcntlArg::F_GETFL).map_err(cver)?;
+        if flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "can't upgrade a lock on a file opened read-only",
+            ));
+        }
+        let reads: Vec<_> = self
+            .held()
+            .iter()
+            .filter(|range| range.lock_type == LockType::Read)
+            .copied()
+            .collect();
+        for range in reads {
+            self.set_lock(LockType::Write, range.start, range.end, blocking)?;
+        }
+        Ok(())
+    }
+
+    /// Turn every write lock held by this handle into a read lock
+    ///
+    /// This never blocks, and ranges that were released or already read
+    /// locked are left alone. Each range's lock type is replaced in place by
+    /// a single `fcntl()`, so the range is never unlocked in between and no
+    /// writer can slip in, except with
+    /// [`flock()` locks](struct.FileLockBuilder.html#method.flock).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .blocking(true)
+    ///                     .lock()?;
+    ///
+    ///    filelock.write_all(b"Hello, world")?;
+    ///
+    ///    // let readers in while we keep the file consistent
+    ///    filelock.downgrade()?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn downgrade(&self) -> Result<()> {
+        let mut held = self.held();
+        let writes: Vec<_> = held
+            .iter()
+            .filter(|range| range.lock_type == LockType::Write)
+            .copied()
+            .collect();
+        for range in writes {
+            let flock = range_flock(LockType::Read, range.start, range.end)?;
+            self.apply(&flock, false)?;
+            self.record(&mut held, range.start, range.end, LockType::Read);
+        }
+        Ok(())
+    }
+
+    /// [`downgrade`](#method.downgrade), then read back the start of the
+    /// locked region
+    ///
+    /// This is the verification step of a write, downgrade, read back flow:
+    /// the read only happens once other readers are let in, and reads from
+    /// the first byte held by this handle with `pread()`, whatever the file
+    /// position. It stops at the end of the first held range, at the end of
+    /// the file or once `buf` is full, and returns the number of bytes read.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::new("record.txt")
+    ///                         .writeable(true)
+    ///                         .blocking(true)
+    ///                         .range(0, 5)
+    ///                         .lock()?;
+    ///    filelock.write_all(b"hello")?;
+    ///
+    ///    let mut buf = [0; 5];
+    ///    let n = filelock.downgrade_and_read(&mut buf)?;
+    ///    assert_eq!(&buf[..n], b"hello");
+    ///    # let _ = std::fs::remove_file("record.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn downgrade_and_read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.downgrade()?;
+        let first = match self.held().iter().next() {
+            Some(&first) => first,
+            None => return Ok(0),
+        };
+        let len = match first.end {
+            Some(end) => buf.len().min((end - first.start) as usize),
+            None => buf.len(),
+        };
+
+        let mut read = 0;
+        while read < len {
+            let offset = first.start + read as u64;
+            match self.file.read_at(&mut buf[read..len], offset) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(read)
+    }
+
+    /// Whether I/O on the locked file blocks, i.e. `O_NONBLOCK` is not set
+    ///
+    /// *Note:* `O_NONBLOCK` only governs reads and writes. Whether acquiring
+    /// a lock waits is decided solely by the `blocking` flag, which selects
+    /// between `F_SETLKW` and `F_SETLK`, whatever the file status flags are.
+    pub fn blocking_io(&self) -> Result<bool> {
+        let flags =
+            fcntl(self.file.as_raw_fd(), FcntlArg::F_GETFL).map_err(cver)?;
+        Ok(flags & libc::O_NONBLOCK == 0)
+    }
+
+    /// Run any `fcntl()` command on the locked file descriptor
+    ///
+    /// Errors are mapped to `std::io::Error` the same way as for the rest of
+    /// this crate. This is meant for commands such as `F_GETFL`/`F_SETFL` or
+    /// `F_GETFD`/`F_SETFD`; changing locks this way bypasses the handle's
+    /// bookkeeping, so [`is_locked`](#method.is_locked) and `Drop` won't know
+    /// about it.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FcntlArg, FileLock};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::lock_shared("myfile.txt")?;
+    ///    let fd_flags = filelock.fcntl(FcntlArg::F_GETFD)?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn fcntl(&self, arg: FcntlArg) -> Result<libc::c_int> {
+        fcntl(self.file.as_raw_fd(), arg).map_err(cver)
+    }
+
+    /// Buffer writes to the locked file, flushing them before the lock is
+    /// released
+    ///
+    /// See [`LockedBufWriter`](struct.LockedBufWriter.html).
+    pub fn into_buf_writer(self) -> LockedBufWriter {
+        LockedBufWriter {
+            writer: BufWriter::new(self),
+        }
+    }
+
+    /// Buffer reads from the locked file, e.g. to parse it line by line
+    ///
+    /// The lock is held until the reader is dropped, and `into_inner()` on
+    /// the reader gives it back, discarding whatever was read ahead.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("settings.txt", "key=value\n")?;
+    ///    let reader = FileLock::lock_shared("settings.txt")?.into_buf_reader();
+    ///    for line in reader.lines() {
+    ///        println!("{}", line?);
+    ///    }
+    ///    # let _ = std::fs::remove_file("settings.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn into_buf_reader(self) -> BufReader<FileLock> {
+        BufReader::new(self)
+    }
+
+    /// Map the locked file into memory, read-only
+    ///
+    /// The map borrows the lock, so it can't outlive the `FileLock`. It
+    /// covers the file as long as it is now, and only makes sense when the
+    /// lock covers all of it. Requires the `mmap` feature.
+    ///
+    /// # Safety
+    ///
+    /// The map derefs to a `&[u8]` that is really the file itself, so the
+    /// file must not change while the map is in use: not through this or
+    /// another handle, e.g. with `write_at` or `set_len`, and not by other
+    /// processes. Advisory locks don't stop processes that ignore them, and
+    /// [`unlock`](#method.unlock) releases the lock even while the map
+    /// exists. Accessing the map after the file was truncated raises
+    /// `SIGBUS`.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("table.bin", [1, 2, 3])?;
+    ///    let filelock = FileLock::lock_shared("table.bin")?;
+    ///    // nothing else writes table.bin without holding the lock
+    ///    let map = unsafe { filelock.map()? };
+    ///    assert_eq!(map[..], [1, 2, 3]);
+    ///    # let _ = std::fs::remove_file("table.bin");
+    ///    Ok(())
+    ///}
+    ///```
+    #[cfg(feature = "mmap")]
+    pub unsafe fn map(&self) -> Result<LockedMap<'_>> {
+        LockedMap::new(self)
+    }
+
+    /// Map the locked file into memory for reading and writing
+    ///
+    /// Like [`map`](#method.map), but the file must be open for writing
+    /// and changes to the map go to the file. Requires the `mmap` feature.
+    ///
+    /// # Safety
+    ///
+    /// As for [`map`](#method.map), nothing else may read or change the
+    /// file while the map is in use.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::lock_exclusive("counter.bin")?;
+    ///    filelock.set_len(8)?;
+    ///    // nothing else uses counter.bin without holding the lock
+    ///    let mut map = unsafe { filelock.map_mut()? };
+    ///    map[0] = map[0].wrapping_add(1);
+    ///    map.flush()?;
+    ///    # let _ = std::fs::remove_file("counter.bin");
+    ///    Ok(())
+    ///}
+    ///```
+    #[cfg(feature = "mmap")]
+    pub unsafe fn map_mut(&mut self) -> Result<LockedMapMut<'_>> {
+        LockedMapMut::new(self)
+    }
+
+    /// Like `read()`, but retrying as long as it fails with
+    /// `ErrorKind::Interrupted`
+    ///
+    /// Reads from FIFOs and other special files can be interrupted by
+    /// signals before any data arrives. Other errors, and short reads, are
+    /// returned as they are.
+    pub fn read_retry(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.file.read(buf) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    /// Write all of `buf`, retrying writes that fail with
+    /// `ErrorKind::Interrupted`
+    ///
+    /// Other errors, such as `ErrorKind::BrokenPipe` when the reader of a
+    /// FIFO went away, are returned right away, in which case part of `buf`
+    /// may have been written.
+    pub fn write_all_retry(&mut self, mut buf: &[u8]) -> Result<()> {
+        while !buf.is_empty() {
+            match self.file.write(buf) {
+                Ok(0) => {
+                    return Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                Ok(n) => buf = &buf[n..],
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Open all `paths` and lock each file entirely, by ascending
+    /// `key_fn(path, file)`, returning the locks in the order of `paths`
+    fn lock_many_with<P, K, F>(
+        paths: &[P],
+        writeable: bool,
+        key_fn: F,
+    ) -> Result<Vec<FileLock>>
+    where
+        P: AsRef<Path>,
+        K: Ord,
+        F: Fn(&Path, &File) -> Result<K>,
+    {
+        let files = paths
+            .iter()
+            .map(|path| open(path, writeable))
+            .collect::<Result<Vec<_>>>()?;
+        let keys = paths
+            .iter()
+            .zip(&files)
+            .map(|(path, file)| key_fn(path.as_ref(), file))
+            .collect::<Result<Vec<_>>>()?;
+        let mut order: Vec<usize> = (0..files.len()).collect();
+        order.sort_by(|&a, &b| keys[a].cmp(&keys[b]));
+        let locks: Vec<FileLock> =
+            files.into_iter().map(FileLock::unlocked).collect();
+        for i in order {
+            locks[i].lock_range(0, 0, true, writeable)?;
+        }
+        Ok(locks)
+    }
+
+    /// Lock the whole file, waiting on a helper thread for at most `timeout`
+    fn lock_whole_timeout(
+        &self,
+        writeable: bool,
+        timeout: Duration,
+    ) -> Result<()> {
+        let lock_type = if writeable {
+            LockType::Write
+        } else {
+            LockType::Read
+        };
+        let flock = range_flock(lock_type, 0, None)?;
+        let mut held = self.held();
+        interrupt::setlkw_timeout(
+            &self.file,
+            &flock,
+            interrupt::Wait::Setlkw,
+            timeout,
+        )?;
+        self.record(&mut held, 0, None, lock_type);
+        Ok(())
+    }
+
+    /// Lock all `ranges` in order, releasing those already acquired if one
+    /// of them fails
+    fn lock_all(
+        &self,
+        ranges: &[(u64, u64)],
+        blocking: bool,
+        writeable: bool,
+    ) -> Result<()> {
+        for (i, &(start, len)) in ranges.iter().enumerate() {
+            if let Err(e) = self.lock_range(start, len, blocking, writeable) {
+                for &(start, len) in &ranges[..i] {
+                    self.unlock_range(start, len)?;
+                }
+                return Err(match e.kind() {
+                    ErrorKind::WouldBlock if !blocking => Error::new(
+                        ErrorKind::WouldBlock,
+                        format!(
+                            "range {} ({}+{}) is locked by another process",
+                            i, start, len
+                        ),
+                    ),
+                    kind => Error::new(
+                        kind,
+                        format!("range {} ({}+{}): {}", i, start, len, e),
+                    ),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn set_lock(
+        &self,
+        lock_type: LockType,
+        start: u64,
+        end: Option<u64>,
+        blocking: impl Into<Block>,
+    ) -> Result<()> {
+        let blocking = blocking.into();
+        let flock = range_flock(lock_type, start, end)?;
+        let mut held = self.held();
+        #[cfg(feature = "events")]
+        if events::enabled() {
+            return self.set_lock_events(held, flock, end, blocking);
+        }
+        self.apply(&flock, blocking)?;
+        self.record(&mut held, start, end, lock_type);
+        Ok(())
+    }
+
+    /// `set_lock` reporting each step to the event sink
+    #[cfg(feature = "events")]
+    fn set_lock_events(
+        &self,
+        mut held: MutexGuard<'_, RangeMap>,
+        flock: libc::flock,
+        end: Option<u64>,
+        blocking: Block,
+    ) -> Result<()> {
+        use events::emit;
+
+        let lock_type = LockType::from_raw(flock.l_type);
+        let start = flock.l_start as u64;
+        let fd = self.file.as_raw_fd();
+        if lock_type == LockType::Unlocked {
+            self.apply(&flock, false)?;
+            self.record(&mut held, start, end, lock_type);
+            emit(LockEventKind::Released, fd, start, end, lock_type);
+            return Ok(());
+        }
+
+        emit(LockEventKind::Attempt, fd, start, end, lock_type);
+        match self.apply(&flock, false) {
+            Err(e)
+                if !matches!(blocking, Block::No)
+                    && e.kind() == ErrorKind::WouldBlock =>
+            {
+                emit(LockEventKind::Blocked, fd, start, end, lock_type);
+                self.apply(&flock, blocking)?;
+            }
+            result => result?,
+        }
+        self.record(&mut held, start, end, lock_type);
+        emit(LockEventKind::Acquired, fd, start, end, lock_type);
+        Ok(())
+    }
+
+    /// Hand a lock change to the backend
+    fn apply(
+        &self,
+        flock: &libc::flock,
+        block: impl Into<Block>,
+    ) -> Result<()> {
+        let wait = match &self.backend {
+            Backend::Fcntl => interrupt::Wait::Setlkw,
+            #[cfg(target_os = "linux")]
+            Backend::Ofd => interrupt::Wait::OfdSetlkw,
+            Backend::Flock => {
+                if !whole_file(flock) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "flock() can only lock whole files",
+                    ));
+                }
+                interrupt::Wait::Flock
+            }
+            Backend::Link(link) => {
+                if !whole_file(flock) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        "lock files can only lock whole files",
+                    ));
+                }
+                let lock_type = LockType::from_raw(flock.l_type);
+                return link.apply(lock_type, block.into());
+            }
+            Backend::Noop => return Ok(()),
+        };
+        match block.into() {
+            Block::No => wait.try_once(self.file.as_raw_fd(), flock),
+            Block::Forever(on_interrupt) => {
+                interrupt::setlkw(&self.file, flock, wait, on_interrupt)
+            }
+            Block::For(timeout) => {
+                interrupt::setlkw_timeout(&self.file, flock, wait, timeout)
+            }
+            Block::Cancellable(cancel, timeout) => {
+                interrupt::setlkw_cancellable(
+                    &self.file, flock, wait, timeout, &cancel,
+                )
+            }
+        }
+    }
+
+    /// Take the `File` out without unlocking it
+    fn into_file(self) -> File {
+        #[cfg(feature = "leak-check")]
+        leak::forget(self.file.as_raw_fd());
+        let mut this = std::mem::ManuallyDrop::new(self);
+        // Safety: `this` is never used or dropped again, so every field is
+        // either moved out or dropped exactly once
+        unsafe {
+            std::ptr::drop_in_place(&mut this.held);
+            std::ptr::drop_in_place(&mut this.backend);
+            std::ptr::drop_in_place(&mut this.on_drop);
+            std::ptr::read(&this.file)
+        }
+    }
+
+    fn unlocked(file: File) -> FileLock {
+        FileLock {
+            file,
+            held: Mutex::default(),
+            locked: AtomicBool::new(false),
+            backend: Backend::Fcntl,
+            on_drop: DropAction::Unlock,
+        }
+    }
+
+    /// Update `held` after the kernel applied a lock change
+    fn record(
+        &self,
+        held: &mut RangeMap,
+        start: u64,
+        end: Option<u64>,
+        lock_type: LockType,
+    ) {
+        held.set(start, end, lock_type);
+        self.locked.store(!held.is_empty(), Ordering::SeqCst);
+        #[cfg(feature = "leak-check")]
+        leak::update(self.file.as_raw_fd(), held);
+    }
+
+    fn held(&self) -> MutexGuard<'_, RangeMap> {
+        self.held.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Atomically add `by` to an integer counter stored in a file
+    ///
+    /// The file is created if needed and exclusively locked (blocking) for
+    /// the whole read-modify-write cycle. An empty file counts as zero.
+    /// The new value is synced to disk before the lock is released.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{CounterFormat, FileLock};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let value = FileLock::increment_counter(
+    ///        "counter.txt",
+    ///        1,
+    ///        CounterFormat::Text,
+    ///    )?;
+    ///    println!("counter is now {}", value);
+    ///    # let _ = std::fs::remove_file("counter.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn increment_counter(
+        file_path: impl AsRef<Path>,
+        by: i64,
+        format: CounterFormat,
+    ) -> Result<i64> {
+        let mut filelock = FileLock::lock_exclusive(file_path)?;
+
+        let mut buf = Vec::new();
+        filelock.file.read_to_end(&mut buf)?;
+        let current = format.decode(&buf)?;
+        let value = current.checked_add(by).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidData, "counter overflow")
+        })?;
+
+        replace_contents(&mut filelock.file, &format.encode(value))?;
+        Ok(value)
+    }
+
+    /// Rewrite a file under an exclusive lock, restoring it on failure
+    ///
+    /// The file is locked (blocking) and its current contents handed to
+    /// `rewrite`, which returns the new contents. If `rewrite` fails the file
+    /// is left untouched. If writing the new contents fails, the original
+    /// contents are written back before the error is returned.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    FileLock::transactional_rewrite("myfile.txt", |current| {
+    ///        let mut new = current.to_vec();
+    ///        new.extend_from_slice(b"one more line\n");
+    ///        Ok(new)
+    ///    })
+    ///}
+    ///```
+    ///
+    pub fn transactional_rewrite<F>(
+        file_path: impl AsRef<Path>,
+        rewrite: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let mut filelock = FileLock::lock_exclusive(file_path)?;
+
+        let mut snapshot = Vec::new();
+        filelock.file.read_to_end(&mut snapshot)?;
+        let contents = rewrite(&snapshot)?;
+
+        if let Err(e) = replace_contents(&mut filelock.file, &contents) {
+            let _ = replace_contents(&mut filelock.file, &snapshot);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Read-modify-write a file under an exclusive lock
+    ///
+    /// The file is created if needed and locked (blocking), its contents are
+    /// passed to `f`, and what `f` returns replaces them and is synced to
+    /// disk before the lock is released. This is
+    /// [`transactional_rewrite`](#method.transactional_rewrite) for updates
+    /// that can't fail.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    FileLock::update("myfile.txt", |contents| contents.to_ascii_uppercase())
+    ///}
+    ///```
+    ///
+    pub fn update<F>(file_path: impl AsRef<Path>, f: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        FileLock::transactional_rewrite(file_path, |current| Ok(f(current)))
+    }
+
+    /// Lock a file exclusively (blocking), writing `default` to it if it is
+    /// new or empty
+    ///
+    /// The file is created if needed, and checked and initialized under the
+    /// lock, so of several processes racing to start only one writes the
+    /// defaults. Returns the lock, positioned at the start of the file, and
+    /// whether `default` was written. `default` is synced to disk before
+    /// returning.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let (mut filelock, created) =
+    ///        FileLock::init_if_absent("config.txt", b"verbose = false\n")?;
+    ///    let mut config = String::new();
+    ///    filelock.read_to_string(&mut config)?;
+    ///    # let _ = std::fs::remove_file("config.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn init_if_absent(
+        file_path: impl AsRef<Path>,
+        default: &[u8],
+    ) -> Result<(FileLock, bool)> {
+        let mut filelock = FileLock::lock_exclusive(file_path)?;
+        let empty = filelock.file.metadata()?.len() == 0;
+        if empty {
+            replace_contents(&mut filelock.file, default)?;
+            filelock.file.seek(SeekFrom::Start(0))?;
+        }
+        Ok((filelock, empty))
+    }
+
+    /// Create a new file, lock it exclusively (blocking) and write `initial`
+    /// to it
+    ///
+    /// The file is created with `O_CREAT | O_EXCL`, so of several processes
+    /// racing only one creates it, and the others fail with an error of kind
+    /// `ErrorKind::AlreadyExists`, as does everyone if the file already
+    /// exists. `initial` is synced to disk before the locked handle is
+    /// returned, positioned at the start of the file.
+    ///
+    /// Unlike [`init_if_absent`](#method.init_if_absent), an existing file is
+    /// never initialized again. A process opening the file right after it
+    /// was created may still lock it before the creator does and find it
+    /// empty; readers should treat an empty file as not provisioned yet.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::{ErrorKind, Result};
+    ///
+    ///fn main() -> Result<()> {
+    ///    # let _ = std::fs::remove_file("state.txt");
+    ///    match FileLock::create_exclusive_new("state.txt", b"generation 0\n") {
+    ///        Ok(filelock) => println!("provisioned"),
+    ///        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+    ///        Err(e) => return Err(e),
+    ///    }
+    ///    # let _ = std::fs::remove_file("state.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn create_exclusive_new(
+        file_path: impl AsRef<Path>,
+        initial: &[u8],
+    ) -> Result<FileLock> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(file_path)?;
+        let mut filelock = FileLock::unlocked(file);
+        filelock.lock_range(0, 0, true, true)?;
+        replace_contents(&mut filelock.file, initial)?;
+        filelock.file.seek(SeekFrom::Start(0))?;
+        Ok(filelock)
+    }
+
+    /// Lock the whole file (blocking) and record the lease in a shared
+    /// registry file
+    ///
+    /// Once the lock is held, a line `<pid> <start> <len> <unix time>
+    /// <path>` is appended to `registry`, created if needed, through
+    /// [`transactional_rewrite`](#method.transactional_rewrite), so the
+    /// registry is itself locked for the update. The line is removed the
+    /// same way just before the lock is released, when the returned
+    /// [`LockBundle`](struct.LockBundle.html) is dropped or released. The
+    /// path is recorded canonicalized, and must not contain a newline.
+    ///
+    /// A process that dies without unwinding leaves its line behind; readers
+    /// of the registry can check the pid, or probe the file with
+    /// [`lock_map`](#method.lock_map).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::path::Path;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let lease =
+    ///        FileLock::lock_registered("myfile.txt", Path::new("leases.txt"), true)?;
+    ///    print!("{}", std::fs::read_to_string("leases.txt")?);
+    ///    lease.release()?;
+    ///    # let _ = std::fs::remove_file("leases.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_registered(
+        file_path: impl AsRef<Path>,
+        registry: &Path,
+        writeable: bool,
+    ) -> Result<LockBundle> {
+        let path = file_path.as_ref();
+        let filelock = FileLock::new(path)
+            .blocking(true)
+            .writeable(writeable)
+            .lock()?;
+        let path = std::fs::canonicalize(path)?;
+        let path = path.to_string_lossy();
+        if path.contains('\n') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "path contains a newline",
+            ));
+        }
+        let since = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let lease = format!("{} 0 0 {} {}\n", std::process::id(), since, path);
+
+        FileLock::transactional_rewrite(registry, |current| {
+            let mut leases = current.to_vec();
+            leases.extend_from_slice(lease.as_bytes());
+            Ok(leases)
+        })?;
+        let registry = registry.to_owned();
+        Ok(LockBundle::new(filelock).before_unlock(move |_| {
+            FileLock::transactional_rewrite(registry, |current| {
+                let lease = lease.as_bytes();
+                let mut leases = current.to_vec();
+                if let Some(at) =
+                    current.windows(lease.len()).position(|line| line == lease)
+                {
+                    leases.drain(at..at + lease.len());
+                }
+                Ok(leases)
+            })
+        }))
+    }
+
+    /// Run `f` under an exclusive lock, choosing what happens to the lock
+    /// if `f` fails
+    ///
+    /// The file is created if needed and locked (blocking). On success the
+    /// lock is released and `f`'s value returned. On failure, with
+    /// `OnError::Release` the lock is released before the error is returned,
+    /// while with `OnError::Hold` the still locked handle is returned in
+    /// [`ScopedError::held`](struct.ScopedError.html#structfield.held), so
+    /// no other process sees the file until the caller has repaired it or
+    /// drops the handle.
+    ///
+    /// `ScopedError` converts into `std::io::Error`, releasing any held
+    /// lock, so `?` works in functions returning `std::io::Result`.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FileLock, OnError};
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let written = FileLock::with_exclusive_policy(
+    ///        "myfile.txt",
+    ///        OnError::Hold,
+    ///        |filelock| filelock.write(b"Hello, world"),
+    ///    );
+    ///    if let Err(mut e) = written {
+    ///        if let Some(filelock) = &mut e.held {
+    ///            filelock.set_len(0)?;
+    ///        }
+    ///        return Err(e.into());
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn with_exclusive_policy<T, F>(
+        file_path: impl AsRef<Path>,
+        on_error: OnError,
+        f: F,
+    ) -> std::result::Result<T, ScopedError>
+    where
+        F: FnOnce(&mut FileLock) -> Result<T>,
+    {
+        let mut filelock = FileLock::lock_exclusive(file_path)
+            .map_err(|error| ScopedError { error, held: None })?;
+        match f(&mut filelock) {
+            Ok(value) => Ok(value),
+            Err(error) => {
+                let held = match on_error {
+                    OnError::Hold => Some(filelock),
+                    OnError::Release => {
+                        filelock.unlock().map_err(|error| ScopedError {
+                            error,
+                            held: None,
+                        })?;
+                        None
+                    }
+                };
+                Err(ScopedError { error, held })
+            }
+        }
+    }
+
+    /// Check whether the filesystem holding a file actually enforces locks
+    ///
+    /// Some filesystems, historically a few FUSE ones, accept record locks
+    /// without enforcing them. This takes an exclusive lock on the file
+    /// (creating it if needed), then forks a child process which tries to
+    /// take a conflicting lock. Returns true if the child was refused.
+    ///
+    /// This costs a `fork()` and a `waitpid()`, so it is best done once at
+    /// startup. The file must not be locked by this process through another
+    /// handle, as closing the probe's handle would release those locks.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    if !FileLock::verify_enforcement("myfile.txt")? {
+    ///        eprintln!("warning: file locks are not enforced here");
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn verify_enforcement(file_path: impl AsRef<Path>) -> Result<bool> {
+        use nix::{
+            sys::wait::{waitpid, WaitStatus},
+            unistd::{fork, ForkResult},
+        };
+        use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+        let c_path = CString::new(file_path.as_ref().as_os_str().as_bytes())?;
+        let _filelock = FileLock::new(&file_path).writeable(true).lock()?;
+        let flock = new_flock(libc::F_WRLCK, 0, 0);
+
+        // only async-signal-safe calls are allowed in the child
+        match unsafe { fork() }.map_err(cver)? {
+            ForkResult::Child => unsafe {
+                let fd = libc::open(c_path.as_ptr(), libc::O_RDWR);
+                let status = if fd < 0 {
+                    2
+                } else if libc::fcntl(fd, libc::F_SETLK, &flock) == 0 {
+                    1
+                } else {
+                    match Errno::last() {
+                        Errno::EAGAIN | Errno::EACCES => 0,
+                        _ => 2,
+                    }
+                };
+                libc::_exit(status)
+            },
+            ForkResult::Parent { child } => loop {
+                match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, 0)) => return Ok(true),
+                    Ok(WaitStatus::Exited(_, 1)) => return Ok(false),
+                    Ok(_) => {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            "lock probe process failed",
+                        ))
+                    }
+                    Err(Errno::EINTR) => continue,
+                    Err(e) => return Err(cver(e)),
+                }
+            },
+        }
+    }
+
+    /// Report which regular files in a directory are locked, and by whom
+    ///
+    /// Every regular file directly inside `dir` (following symlinks) is
+    /// opened read-only and probed with `F_GETLK`. Other entries are skipped.
+    /// Failing to probe an entry doesn't abort the scan, the error is
+    /// recorded for that entry instead. Results are sorted by path.
+    ///
+    /// Nothing is locked by this call, but each file is closed again after
+    /// probing, which, as with any close, releases the `fcntl()` locks this
+    /// process holds on it through other handles, see
+    /// [`backend_drops_on_any_close`](#method.backend_drops_on_any_close).
+    /// `F_GETLK` doesn't report those locks anyway, so don't scan a
+    /// directory holding files locked that way by this process.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    for (path, info) in FileLock::scan_dir(".")? {
+    ///        match info {
+    ///            Ok(Some(info)) => println!("{:?}: held by {}", path, info.pid),
+    ///            Ok(None) => println!("{:?}: free", path),
+    ///            Err(e) => println!("{:?}: {}", path, e),
+    ///        }
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn scan_dir(
+        dir: impl AsRef<Path>,
+    ) -> Result<Vec<(PathBuf, Result<Option<LockInfo>>)>> {
+        let mut results = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            match std::fs::metadata(&path) {
+                Ok(metadata) if !metadata.is_file() => continue,
+                Ok(_) => {}
+                Err(e) => {
+                    results.push((path, Err(e)));
+                    continue;
+                }
+            }
+            let info = File::open(&path).and_then(|file| {
+                Ok(first_conflict(&file, 0, None)?.map(LockInfo::from_flock))
+            });
+            results.push((path, info));
+        }
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(results)
+    }
+
+    /// Find which process is in the way of locking a file
+    ///
+    /// The file is probed with `F_GETLK` for a whole-file lock, exclusive if
+    /// `writeable`, shared otherwise. Returns the pid, type and byte range of
+    /// a lock held by another process that conflicts with it, or `None` if
+    /// the lock could be taken right now. If several locks conflict, the
+    /// kernel reports one of them.
+    ///
+    /// Nothing is locked by this call. The file is opened read-only and
+    /// closed again, which, as with any close, releases the locks this
+    /// process holds on it through other handles.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::{ErrorKind, Result};
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("myfile.txt", b"")?;
+    ///    match FileLock::new("myfile.txt").writeable(true).try_lock() {
+    ///        Ok(filelock) => println!("got it"),
+    ///        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+    ///            if let Some(info) = FileLock::blocking_holder("myfile.txt", true)? {
+    ///                eprintln!("held by pid {}", info.pid);
+    ///            }
+    ///        }
+    ///        Err(e) => return Err(e),
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn blocking_holder(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+    ) -> Result<Option<LockInfo>> {
+        let file = File::open(file_path)?;
+        let lock_type = if writeable {
+            LockType::Write
+        } else {
+            LockType::Read
+        };
+        Ok(conflicting_lock(&file, lock_type)?.map(LockInfo::from_flock))
+    }
+
+    /// Whether another process holds a lock on any part of a file
+    ///
+    /// This only tests, with `F_GETLK`, and doesn't take a lock, so a monitor
+    /// can report the status of a lock without getting in the owner's way.
+    /// Shared locks count as well, see [`can_lock`](#method.can_lock) to
+    /// tell whether a shared lock could be taken. Locks held by this process
+    /// aren't reported, and the same caveat as for
+    /// [`blocking_holder`](#method.blocking_holder) applies about the file
+    /// being closed afterwards.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("myfile.txt", b"")?;
+    ///    if FileLock::is_locked_at("myfile.txt")? {
+    ///        println!("in use");
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn is_locked_at(file_path: impl AsRef<Path>) -> Result<bool> {
+        Ok(FileLock::blocking_holder(file_path, true)?.is_some())
+    }
+
+    /// Whether a whole-file lock, exclusive if `writeable`, shared otherwise,
+    /// could be taken on a file right now
+    ///
+    /// Like [`is_locked_at`](#method.is_locked_at), this doesn't take the
+    /// lock, so someone else may well take it before the caller does.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("myfile.txt", b"")?;
+    ///    if !FileLock::can_lock("myfile.txt", false)? {
+    ///        println!("a writer is busy");
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn can_lock(
+        file_path: impl AsRef<Path>,
+        writeable: bool,
+    ) -> Result<bool> {
+        Ok(FileLock::blocking_holder(file_path, writeable)?.is_none())
+    }
+
+    /// Wait until nobody holds a lock on the file, using inotify instead of
+    /// polling
+    ///
+    /// The file is watched for `IN_CLOSE_WRITE`/`IN_CLOSE_NOWRITE` events, as
+    /// a lock holder usually closes the file when it's done, and probed with
+    /// `F_GETLK` after each one. Returns once the whole file is free, or an
+    /// error of kind `ErrorKind::TimedOut` after `timeout`.
+    ///
+    /// This is best effort: a holder that releases its lock without closing
+    /// the file generates no event, so the release is only noticed on the
+    /// next close by anyone, or by the final probe when `timeout` expires.
+    /// Nothing is locked by this call, so the file can be locked again by
+    /// someone else before the caller gets to it.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::time::Duration;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("myfile.txt", b"")?;
+    ///    FileLock::wait_free_inotify("myfile.txt", Duration::from_secs(1))?;
+    ///    let filelock = FileLock::new("myfile.txt").lock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    #[cfg(target_os = "linux")]
+    pub fn wait_free_inotify(
+        file_path: impl AsRef<Path>,
+        timeout: Duration,
+    ) -> Result<()> {
+        use nix::{
+            poll::{poll, PollFd, PollFlags},
+            sys::inotify::{AddWatchFlags, InitFlags, Inotify},
+        };
+
+        /// `Inotify` doesn't close its descriptor on drop
+        struct Watch(Inotify);
+        impl Drop for Watch {
+            fn drop(&mut self) {
+                let _ = nix::unistd::close(self.0.as_raw_fd());
+            }
+        }
+
+        let deadline = std::time::Instant::now() + timeout;
+        let file = File::open(&file_path)?;
+        let watch = Watch(
+            Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+                .map_err(cver)?,
+        );
+        watch
+            .0
+            .add_watch(
+                file_path.as_ref(),
+                AddWatchFlags::IN_CLOSE_WRITE | AddWatchFlags::IN_CLOSE_NOWRITE,
+            )
+            .map_err(cver)?;
+
+        loop {
+            if first_conflict(&file, 0, None)?.is_none() {
+                return Ok(());
+            }
+            let remaining =
+                deadline.saturating_duration_since(std::time::Instant::now());
+            if remaining == Duration::ZERO {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "timed out waiting for lock to be released",
+                ));
+            }
+            // round up so we don't spin for the last fraction of a ms
+            let ms = (remaining.as_micros() + 999) / 1000;
+            let ms = ms.min(libc::c_int::MAX as u128) as libc::c_int;
+            let mut fds = [PollFd::new(watch.0.as_raw_fd(), PollFlags::POLLIN)];
+            match poll(&mut fds, ms) {
+                Ok(0) | Err(Errno::EINTR) => {}
+                Ok(_) => match watch.0.read_events() {
+                    Ok(_) | Err(Errno::EAGAIN) => {}
+                    Err(e) => return Err(cver(e)),
+                },
+                Err(e) => return Err(cver(e)),
+            }
+        }
+    }
+
+    /// Make sure only one instance of a program runs, using a pidfile
+    ///
+    /// The pidfile is created if needed and a non-blocking exclusive lock is
+    /// attempted on it. If it is obtained, the file is overwritten with the
+    /// PID of this process. Otherwise the PID of the running instance is read
+    /// back from the file, or queried with `F_GETLK` if the file doesn't
+    /// hold one yet.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FileLock, SingletonResult};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    match FileLock::singleton("myservice.pid")? {
+    ///        SingletonResult::Acquired(_lock) => {
+    ///            // run the daemon while holding _lock
+    ///        }
+    ///        SingletonResult::AlreadyRunning { pid } => {
+    ///            eprintln!("already running as {:?}", pid);
+    ///        }
+    ///    }
+    ///    # let _ = std::fs::remove_file("myservice.pid");
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn singleton(file_path: impl AsRef<Path>) -> Result<SingletonResult> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&file_path)?;
+        let flock = new_flock(libc::F_WRLCK, 0, 0);
+        match fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&flock)) {
+            Ok(_) => {
+                let mut filelock = FileLock::unlocked(file);
+                filelock.record(&mut filelock.held(), 0, None, LockType::Write);
+                filelock.file.set_len(0)?;
+                writeln!(filelock.file, "{}", std::process::id())?;
+                filelock.file.sync_data()?;
+                Ok(SingletonResult::Acquired(filelock))
+            }
+            Err(Errno::EAGAIN) | Err(Errno::EACCES) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents)?;
+                let pid = match contents.trim().parse() {
+                    Ok(pid) => Some(pid),
+                    Err(_) => {
+                        first_conflict(&file, 0, None)?.map(|lock| lock.l_pid)
+                    }
+                };
+                Ok(SingletonResult::AlreadyRunning { pid })
+            }
+            Err(e) => Err(cver(e)),
+        }
+    }
+
+    /// Describe how a byte range of a file is locked by other processes
+    ///
+    /// The range `[start, start + len)` is walked with repeated `F_GETLK`
+    /// probes and returned as contiguous segments covering the whole span,
+    /// free gaps included. A `len` of 0 means up to the end of the file, in
+    /// which case the last segment has a `len` of 0 as well.
+    ///
+    /// *Note:* `fcntl()` never reports the calling process's own locks, so
+    /// those show up as `Unlocked`. Where several processes hold overlapping
+    /// read locks, each segment names one of them.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("myfile.txt", b"")?;
+    ///    for segment in FileLock::lock_map("myfile.txt", 0, 0)? {
+    ///        println!("{:?}", segment);
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    ///
+    pub fn lock_map(
+        file_path: impl AsRef<Path>,
+        start: u64,
+        len: u64,
+    ) -> Result<Vec<LockSegment>> {
+        let file = File::open(&file_path)?;
+        let end = range_end(start, len)?;
+
+        let mut segments = Vec::new();
+        let mut pos = start;
+        loop {
+            let remaining = end.map_or(0, |end| end - pos);
+            let lock = match first_conflict(&file, pos, end)? {
+                Some(lock) => lock,
+                None => {
+                    segments.push(LockSegment {
+                        start: pos,
+                        len: remaining,
+                        lock_type: LockType::Unlocked,
+                        pid: None,
+                    });
+                    return Ok(segments);
+                }
+            };
+
+            let lock_start = (lock.l_start as u64).max(pos);
+            let lock_end = match lock.l_len {
+                0 => end,
+                l_len => {
+                    let lock_end = (lock.l_start + l_len) as u64;
+                    Some(end.map_or(lock_end, |end| end.min(lock_end)))
+                }
+            };
+            if lock_start > pos {
+                segments.push(LockSegment {
+                    start: pos,
+                    len: lock_start - pos,
+                    lock_type: LockType::Unlocked,
+                    pid: None,
+                });
+            }
+            segments.push(LockSegment {
+                start: lock_start,
+                len: lock_end.map_or(0, |lock_end| lock_end - lock_start),
+                lock_type: LockType::from_raw(lock.l_type),
+                pid: Some(lock.l_pid),
+            });
+
+            match lock_end {
+                Some(lock_end) if Some(lock_end) != end => pos = lock_end,
+                _ => return Ok(segments),
+            }
+        }
+    }
+}
+
+/// Outcome of [`FileLock::singleton`](struct.FileLock.html#method.singleton)
+#[derive(Debug)]
+pub enum SingletonResult {
+    /// This process is the only instance, the lock must be kept alive for as
+    /// long as it runs
+    Acquired(FileLock),
+    /// Another instance holds the lock
+    AlreadyRunning {
+        /// PID of the other instance, if it could be determined
+        pid: Option<libc::pid_t>,
+    },
+}
+
+/// What [`FileLock::with_exclusive_policy`](struct.FileLock.html#method.with_exclusive_policy)
+/// does with the lock when the closure fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnError {
+    /// Keep the lock and hand it back with the error
+    Hold,
+    /// Release the lock before returning the error
+    Release,
+}
+
+/// What a blocking lock does when a signal interrupts its wait, as set with
+/// [`FileLockBuilder::on_interrupt`](struct.FileLockBuilder.html#method.on_interrupt)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnInterrupt {
+    /// Fail with an error of kind `ErrorKind::Interrupted`
+    #[default]
+    Return,
+    /// Keep waiting, however many times the wait is interrupted
+    Retry,
+    /// Keep waiting after up to this many interruptions, then fail with an
+    /// error of kind `ErrorKind::Interrupted`
+    RetryUpTo(u32),
+}
+
+impl OnInterrupt {
+    /// Whether to wait again after `interrupted` previous interruptions
+    pub(crate) fn retry(self, interrupted: u32) -> bool {
+        match self {
+            OnInterrupt::Return => false,
+            OnInterrupt::Retry => true,
+            OnInterrupt::RetryUpTo(n) => interrupted < n,
+        }
+    }
+}
+
+/// What dropping a [`FileLock`](struct.FileLock.html) does, as set with
+/// [`FileLockBuilder::on_drop`](struct.FileLockBuilder.html#method.on_drop)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnDrop {
+    /// Release the locks held, then close the file
+    #[default]
+    Unlock,
+    /// Remove the file if an exclusive lock is still held on it and its path
+    /// still names it, then release the locks and close the file, for lock
+    /// files that only exist while held
+    ///
+    /// A process waiting for the lock meanwhile then gets it on the removed
+    /// file. To handle this, locking with `OnDrop::Delete` checks once the
+    /// lock is granted that the path still names the locked file, and if
+    /// not, opens and locks the file at the path again.
+    Delete,
+    /// Only close the file, letting that release the locks. Record locks
+    /// then stay held as long as the process has the file open through
+    /// another descriptor, and open file description or `flock()` locks as
+    /// long as a duplicate of the descriptor is open, e.g. in a child.
+    Close,
+}
+
+/// Error of [`FileLock::with_exclusive_policy`](struct.FileLock.html#method.with_exclusive_policy)
+#[derive(Debug)]
+pub struct ScopedError {
+    /// What went wrong, either locking the file or in the closure
+    pub error: Error,
+    /// The still locked handle, with `OnError::Hold` when the closure failed
+    pub held: Option<FileLock>,
+}
+
+impl fmt::Display for ScopedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for ScopedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl From<ScopedError> for Error {
+    fn from(e: ScopedError) -> Error {
+        e.error
+    }
+}
+
+/// Error of [`FileLock::lock_timeout_detailed`](struct.FileLock.html#method.lock_timeout_detailed)
+#[derive(Debug)]
+pub struct TimeoutInfo {
+    /// how long was waited in total
+    pub waited: Duration,
+    /// what is left of the timeout, zero once it has expired
+    pub remaining: Duration,
+    /// whether another process held a conflicting lock when the attempt
+    /// failed, as reported by `F_GETLK`, as opposed to the attempt failing
+    /// for another reason
+    pub contended: bool,
+    /// the underlying error, usually of kind `ErrorKind::TimedOut` when
+    /// `contended`
+    pub error: Error,
+}
+
+impl fmt::Display for TimeoutInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} after {:?}", self.error, self.waited)
+    }
+}
+
+impl std::error::Error for TimeoutInfo {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<TimeoutInfo> for Error {
+    fn from(info: TimeoutInfo) -> Error {
+        info.error
+    }
+}
+
+/// Error of [`FileLockBuilder::lock_detailed`](struct.FileLockBuilder.html#method.lock_detailed)
+///
+/// Each variant carries the underlying `io::Error`, which is what the
+/// `From<FileLockError> for io::Error` conversion returns.
+#[derive(Debug)]
+pub enum FileLockError {
+    /// The file couldn't be opened, e.g. it doesn't exist or its permissions
+    /// don't allow it
+    Open(Error),
+    /// Another process holds a conflicting lock and the call didn't wait,
+    /// or gave up waiting
+    Contended(Error),
+    /// A signal interrupted the wait
+    Interrupted(Error),
+    /// The file or its filesystem doesn't support this kind of lock
+    Unsupported(Error),
+    /// Any other failure
+    Io(Error),
+}
+
+impl FileLockError {
+    /// Classify an error returned while locking an already open file
+    fn from_lock(error: Error) -> FileLockError {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                FileLockError::Contended(error)
+            }
+            ErrorKind::Interrupted => FileLockError::Interrupted(error),
+            _ => match error.raw_os_error() {
+                Some(libc::ENOLCK | libc::EOPNOTSUPP | libc::ENOSYS) => {
+                    FileLockError::Unsupported(error)
+                }
+                _ => FileLockError::Io(error),
+            },
+        }
+    }
+
+    /// The underlying error
+    pub fn io_error(&self) -> &Error {
+        match self {
+            FileLockError::Open(e)
+            | FileLockError::Contended(e)
+            | FileLockError::Interrupted(e)
+            | FileLockError::Unsupported(e)
+            | FileLockError::Io(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for FileLockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileLockError::Open(e) => write!(f, "can't open file: {}", e),
+            FileLockError::Contended(e) => write!(f, "file is locked: {}", e),
+            FileLockError::Interrupted(e) => write!(f, "interrupted: {}", e),
+            FileLockError::Unsupported(e) => {
+                write!(f, "locking not supported: {}", e)
+            }
+            FileLockError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FileLockError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+impl From<FileLockError> for Error {
+    fn from(e: FileLockError) -> Error {
+        match e {
+            FileLockError::Open(e)
+            | FileLockError::Contended(e)
+            | FileLockError::Interrupted(e)
+            | FileLockError::Unsupported(e)
+            | FileLockError::Io(e) => e,
+        }
+    }
+}
+
+/// Kind of advisory lock held on a byte range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum LockType {
+    /// No lock is held
+    Unlocked,
+    /// Shared lock (`F_RDLCK`)
+    Read,
+    /// Exclusive lock (`F_WRLCK`)
+    Write,
+}
+
+impl LockType {
+    fn to_raw(self) -> libc::c_int {
+        match self {
+            LockType::Unlocked => libc::F_UNLCK,
+            LockType::Read => libc::F_RDLCK,
+            LockType::Write => libc::F_WRLCK,
+        }
+    }
+
+    fn from_raw(l_type: libc::c_short) -> Self {
+        match l_type as libc::c_int {
+            libc::F_RDLCK => LockType::Read,
+            libc::F_WRLCK => LockType::Write,
+            _ => LockType::Unlocked,
+        }
+    }
+}
+
+/// A lock held by another process
+///
+/// With the `serde` feature it implements `serde::Serialize`, e.g. for
+/// tools reporting who is in the way as JSON.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LockInfo {
+    /// offset of the first locked byte
+    pub start: u64,
+    /// length of the locked range, 0 meaning up to the end of the file
+    pub len: u64,
+    /// `Read` or `Write`
+    pub lock_type: LockType,
+    /// process holding the lock
+    pub pid: libc::pid_t,
+}
+
+impl LockInfo {
+    fn from_flock(flock: libc::flock) -> Self {
+        LockInfo {
+            start: flock.l_start as u64,
+            len: flock.l_len as u64,
+            lock_type: LockType::from_raw(flock.l_type),
+            pid: flock.l_pid,
+        }
+    }
+}
+
+/// Contiguous byte range sharing the same lock state, as returned by
+/// [`FileLock::lock_map`](struct.FileLock.html#method.lock_map)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct LockSegment {
+    /// offset of the first byte of the segment
+    pub start: u64,
+    /// length of the segment, 0 meaning up to the end of the file
+    pub len: u64,
+    /// how the segment is locked
+    pub lock_type: LockType,
+    /// process holding the lock, `None` for unlocked segments
+    pub pid: Option<libc::pid_t>,
+}
+
+/// On-disk representation of a counter used by
+/// [`FileLock::increment_counter`](struct.FileLock.html#method.increment_counter)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterFormat {
+    /// Decimal ASCII text, optionally surrounded by whitespace
+    Text,
+    /// 8 byte little-endian two's complement integer
+    Binary,
+}
+
+impl CounterFormat {
+    fn decode(self, buf: &[u8]) -> Result<i64> {
+        match self {
+            CounterFormat::Text => {
+                let text = std::str::from_utf8(buf)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+                    .trim();
+                if text.is_empty() {
+                    return Ok(0);
+                }
+                text.parse()
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+            }
+            CounterFormat::Binary => match buf.len() {
+                0 => Ok(0),
+                8 => {
+                    let mut bytes = [0; 8];
+                    bytes.copy_from_slice(buf);
+                    Ok(i64::from_le_bytes(bytes))
+                }
+                _ => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "binary counter must be 8 bytes long",
+                )),
+            },
+        }
+    }
+
+    fn encode(self, value: i64) -> Vec<u8> {
+        match self {
+            CounterFormat::Text => format!("{}\n", value).into_bytes(),
+            CounterFormat::Binary => value.to_le_bytes().to_vec(),
+        }
+    }
+}
+
+impl Read for FileLock {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.file.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
+        self.file.read_vectored(bufs)
+    }
+}
+
+impl Write for FileLock {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        self.file.write_vectored(bufs)
+    }
+}
+
+impl Seek for FileLock {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+/// Gives direct access to the `File` methods, e.g. `filelock.metadata()`
+///
+/// Like [`file_mut`](struct.FileLock.html#method.file_mut), this hands out
+/// the locked `File` itself, so replacing or closing it drops the lock.
+/// `File`'s own `lock()` family of methods uses `flock()`, which is
+/// unrelated to the record locks held here.
+impl Deref for FileLock {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl DerefMut for FileLock {
+    fn deref_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+}
+
+impl AsRawFd for FileLock {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Closes nothing and keeps the locks held, like
+/// [`persist_lock`](struct.FileLock.html#method.persist_lock)
+impl IntoRawFd for FileLock {
+    fn into_raw_fd(self) -> RawFd {
+        self.into_file().into_raw_fd()
+    }
+}
+
+impl AsFd for FileLock {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.file.as_fd()
+    }
+}
+
+impl FileExt for FileLock {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<usize> {
+        self.file.write_at(buf, offset)
+    }
+}
+
+/// Builder to create [`FileLock`](struct.FileLock.html)
+///
+/// blocking and writeable default to false, and the whole file is locked
+///
+/// A builder can be cloned to keep it around as a template and lock
+/// several files with the same settings
+///
+/// # Examples
+///
+///```
+///use file_locker::FileLock;
+///use std::io::Result;
+///
+///fn main() -> Result<()> {
+///    let template = FileLock::new("a.txt").writeable(true).blocking(true);
+///
+///    let a = template.clone().lock()?;
+///    let b = template.path(String::from("b.txt")).lock()?;
+///    # let _ = std::fs::remove_file("a.txt");
+///    # let _ = std::fs::remove_file("b.txt");
+///    Ok(())
+///}
+///```
+#[derive(Clone)]
+pub struct FileLockBuilder<T> {
+    file_path: T,
+    blocking: bool,
+    writeable: bool,
+    range: (u64, u64),
+    validate: Option<Validator>,
+    enabled: bool,
+    ofd: bool,
+    flock: bool,
+    link: bool,
+    retries: u32,
+    backoff: Backoff,
+    cancel: Option<CancelToken>,
+    on_interrupt: OnInterrupt,
+    on_drop: OnDrop,
+    holder_label: Option<String>,
+    open_options: Option<OpenOptions>,
+    append: bool,
+    create_new: bool,
+    truncate: bool,
+    mode: Option<u32>,
+    create: Option<bool>,
+    exclusive: Option<bool>,
+    cloexec: bool,
+    dir: Option<Arc<Result<OwnedFd>>>,
+}
+
+type Validator = Arc<dyn Fn(&File) -> Result<()> + Send + Sync>;
+
+impl<T: fmt::Debug> fmt::Debug for FileLockBuilder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileLockBuilder")
+            .field("file_path", &self.file_path)
+            .field("blocking", &self.blocking)
+            .field("writeable", &self.writeable)
+            .field("range", &self.range)
+            .field("validate", &self.validate.is_some())
+            .field("enabled", &self.enabled)
+            .field("ofd", &self.ofd)
+            .field("flock", &self.flock)
+            .field("link", &self.link)
+            .field("retries", &self.retries)
+            .field("backoff", &self.backoff)
+            .field("cancel", &self.cancel)
+            .field("on_interrupt", &self.on_interrupt)
+            .field("on_drop", &self.on_drop)
+            .field("holder_label", &self.holder_label)
+            .field("open_options", &self.open_options)
+            .field("append", &self.append)
+            .field("create_new", &self.create_new)
+            .field("truncate", &self.truncate)
+            .field("mode", &self.mode)
+            .field("create", &self.create)
+            .field("exclusive", &self.exclusive)
+            .field("cloexec", &self.cloexec)
+            .field("dir", &self.dir)
+            .finish()
+    }
+}
+
+impl<T: AsRef<Path>> FileLockBuilder<T> {
+    /// Change the file to lock, keeping all other settings
+    pub fn path<U: AsRef<Path>>(self, file_path: U) -> FileLockBuilder<U> {
+        FileLockBuilder {
+            file_path,
+            blocking: self.blocking,
+            writeable: self.writeable,
+            range: self.range,
+            validate: self.validate,
+            enabled: self.enabled,
+            ofd: self.ofd,
+            flock: self.flock,
+            link: self.link,
+            retries: self.retries,
+            backoff: self.backoff,
+            cancel: self.cancel,
+            on_interrupt: self.on_interrupt,
+            on_drop: self.on_drop,
+            holder_label: self.holder_label,
+            open_options: self.open_options,
+            append: self.append,
+            create_new: self.create_new,
+            truncate: self.truncate,
+            mode: self.mode,
+            create: self.create,
+            exclusive: self.exclusive,
+            cloexec: self.cloexec,
+            dir: self.dir,
+        }
+    }
+
+    /// Set lock to blocking mode
+    ///
+    /// This only affects waiting for the lock, see
+    /// [`FileLock::blocking_io`](struct.FileLock.html#method.blocking_io)
+    pub fn blocking(mut self, v: bool) -> Self {
+        self.blocking = v;
+        self
+    }
+
+    /// Open file as writeable and get exclusive lock
+    pub fn writeable(mut self, v: bool) -> Self {
+        self.writeable = v;
+        self
+    }
+
+    /// Get an exclusive lock rather than a shared one
+    ///
+    /// Defaults to [`writeable`](#method.writeable). A shared lock on a
+    /// writeable file suits processes that write disjoint parts of it, e.g.
+    /// records at offsets of their own with `write_at`, while keeping out a
+    /// process that needs the whole file to itself. An exclusive lock on a
+    /// file that isn't writeable only works with [`flock`](#method.flock).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::os::unix::fs::FileExt;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("records.txt")
+    ///                     .writeable(true)
+    ///                     .exclusive(false)
+    ///                     .lock()?;
+    ///    filelock.write_at(b"record", 64)?;
+    ///    # let _ = std::fs::remove_file("records.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn exclusive(mut self, v: bool) -> Self {
+        self.exclusive = Some(v);
+        self
+    }
+
+    /// Only lock `len` bytes starting at `start`
+    ///
+    /// A `len` of 0 locks up to the end of the file, however far it grows
+    pub fn range(mut self, start: u64, len: u64) -> Self {
+        self.range = (start, len);
+        self
+    }
+
+    /// Check the file after opening it but before locking it
+    ///
+    /// If `validate` returns an error, the file is closed without being
+    /// locked and [`lock`](#method.lock) returns that error. This is meant to
+    /// reject unexpected files early, e.g. by checking a magic number; as the
+    /// file isn't locked yet, checks that need a stable view of the contents
+    /// belong after locking.
+    ///
+    /// `validate` is called on every `lock()`, including from clones of this
+    /// builder.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::{Error, ErrorKind, Result};
+    ///use std::os::unix::fs::FileExt;
+    ///
+    ///fn main() -> Result<()> {
+    ///    # std::fs::write("mydb.bin", b"MYDB")?;
+    ///    let filelock = FileLock::new("mydb.bin")
+    ///        .writeable(true)
+    ///        .validate(|file| {
+    ///            let mut magic = [0; 4];
+    ///            file.read_exact_at(&mut magic, 0)?;
+    ///            if &magic != b"MYDB" {
+    ///                return Err(Error::new(ErrorKind::InvalidData, "not a db"));
+    ///            }
+    ///            # let _ = std::fs::remove_file("mydb.bin");
+    ///            Ok(())
+    ///        })
+    ///        .lock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn validate<F>(mut self, validate: F) -> Self
+    where
+        F: Fn(&File) -> Result<()> + Send + Sync + 'static,
+    {
+        self.validate = Some(Arc::new(validate));
+        self
+    }
+
+    /// Turn locking on or off, e.g. from configuration
+    ///
+    /// With `enabled(false)`, [`lock`](#method.lock) opens the file as usual
+    /// and returns a no-op `FileLock`: it reads, writes and tracks its ranges
+    /// like any other, so [`is_locked`](struct.FileLock.html#method.is_locked)
+    /// still reports them, but never calls `fcntl()` to lock anything and so
+    /// never blocks or fails because of other processes. This is meant for
+    /// single-process deployments, tests, or filesystems without lock
+    /// support, letting the same code run with or without locking.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let locking = std::env::var_os("NO_LOCKING").is_none();
+    ///    let mut filelock = FileLock::new("myfile.txt")
+    ///                         .writeable(true)
+    ///                         .enabled(locking)
+    ///                         .lock()?;
+    ///    filelock.write_all(b"Hello, World!")?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn enabled(mut self, v: bool) -> Self {
+        self.enabled = v;
+        self
+    }
+
+    /// Use open file description (OFD) locks instead of classic POSIX record
+    /// locks
+    ///
+    /// Classic record locks belong to the process: closing *any* descriptor
+    /// of the file releases them, and two handles in the same process never
+    /// conflict. OFD locks (`F_OFD_SETLK`/`F_OFD_SETLKW`, Linux 3.15+)
+    /// belong to the open file instead, so they are only released with this
+    /// handle, and two handles conflict even within one process, which makes
+    /// them usable between threads. OFD and classic locks on the same file
+    /// do conflict with each other, also within a process.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .ofd(true)
+    ///                     .lock()?;
+    ///    // doesn't release the lock
+    ///    drop(std::fs::File::open("myfile.txt")?);
+    ///    Ok(())
+    ///}
+    ///```
+    #[cfg(target_os = "linux")]
+    pub fn ofd(mut self, v: bool) -> Self {
+        self.ofd = v;
+        self
+    }
+
+    /// Use BSD `flock()` locks instead of `fcntl()` record locks
+    ///
+    /// `flock()` locks the whole file and interoperates with other tools
+    /// using it, such as the `flock(1)` utility. Like OFD locks, they belong
+    /// to the open file rather than the process. They can't lock byte
+    /// ranges: locking or releasing anything but the whole file fails with
+    /// `ErrorKind::InvalidInput`. Turning a lock into another kind, as in
+    /// [`FileLock::downgrade`](struct.FileLock.html#method.downgrade), isn't
+    /// atomic with `flock()`. On Linux `flock()` and `fcntl()` locks don't
+    /// see each other, so [`FileLock::lock_map`](struct.FileLock.html#method.lock_map)
+    /// doesn't report `flock()` locks. Takes precedence over
+    /// [`ofd`](#method.ofd).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    // excludes `flock myfile.txt -c ...` while held
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .flock(true)
+    ///                     .lock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn flock(mut self, v: bool) -> Self {
+        self.flock = v;
+        self
+    }
+
+    /// Lock with a `<path>.lock` file linked into place instead of
+    /// `fcntl()`, for network filesystems whose record locks can't be
+    /// trusted
+    ///
+    /// A uniquely named temporary file holding `hostname:pid:n` is created
+    /// next to the file with `O_EXCL` and hard linked to `<path>.lock`, which
+    /// only succeeds if that doesn't exist, and is checked through the link
+    /// count since `link()` over NFS may report a failure that didn't
+    /// happen. Releasing the lock removes `<path>.lock`, if it still holds
+    /// the handle's id.
+    ///
+    /// This only works between processes using the same convention. There
+    /// is a single kind of lock, so shared locks exclude each other too, and
+    /// only whole files can be locked: anything else fails with
+    /// `ErrorKind::InvalidInput`. Waiting polls the lock file with growing
+    /// intervals. The lock file isn't removed if the process dies while
+    /// holding it. Takes precedence over [`ofd`](#method.ofd) and
+    /// [`flock`](#method.flock).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    // holds myfile.txt.lock
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .link_lockfile(true)
+    ///                     .lock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn link_lockfile(mut self, v: bool) -> Self {
+        self.link = v;
+        self
+    }
+
+    /// Retry a non-blocking lock up to `n` times if the file is held
+    ///
+    /// Between attempts the thread sleeps according to
+    /// [`backoff`](#method.backoff), by default exponentially from 10 ms up
+    /// to 1 s. If the file is still held after the last retry, the error of
+    /// kind `ErrorKind::WouldBlock` is returned. Has no effect on blocking
+    /// locks.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{Backoff, FileLock};
+    ///use std::io::Result;
+    ///use std::time::Duration;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .retries(5)
+    ///                     .backoff(Backoff::Fixed(Duration::from_millis(50)))
+    ///                     .lock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn retries(mut self, n: u32) -> Self {
+        self.retries = n;
+        self
+    }
+
+    /// Set how long to wait between [`retries`](#method.retries)
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Let blocking waits for the lock be aborted with `token`
+    ///
+    /// When [`CancelToken::cancel`](struct.CancelToken.html#method.cancel)
+    /// is called, e.g. from a shutdown handler, a
+    /// [`lock`](#method.lock) or [`lock_timeout`](#method.lock_timeout)
+    /// waiting for the file fails with an error of kind
+    /// `ErrorKind::Interrupted`. Non-blocking locks are unaffected.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{CancelToken, FileLock};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let shutdown = CancelToken::new();
+    ///    let worker = {
+    ///        let shutdown = shutdown.clone();
+    ///        std::thread::spawn(move || {
+    ///            FileLock::new("myfile.txt")
+    ///                .blocking(true)
+    ///                .writeable(true)
+    ///                .cancel_token(shutdown)
+    ///                .lock()
+    ///        })
+    ///    };
+    ///    shutdown.cancel();
+    ///    let _ = worker.join().unwrap();
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn cancel_token(mut self, token: CancelToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Choose what a blocking [`lock`](#method.lock) does when a signal
+    /// interrupts its wait, see [`OnInterrupt`](enum.OnInterrupt.html)
+    ///
+    /// Signals only interrupt the wait if their handler was installed
+    /// without `SA_RESTART`. Waits with a timeout, including those capped
+    /// by [`set_default_max_wait`](fn.set_default_max_wait.html), or with a
+    /// [`cancel_token`](#method.cancel_token) always resume after a signal.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FileLock, OnInterrupt};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .blocking(true)
+    ///                     .writeable(true)
+    ///                     .on_interrupt(OnInterrupt::RetryUpTo(3))
+    ///                     .lock()?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn on_interrupt(mut self, policy: OnInterrupt) -> Self {
+        self.on_interrupt = policy;
+        self
+    }
+
+    /// Choose what happens when the [`FileLock`](struct.FileLock.html) is
+    /// dropped, see [`OnDrop`](enum.OnDrop.html)
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FileLock, OnDrop};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.lock")
+    ///                     .writeable(true)
+    ///                     .on_drop(OnDrop::Delete)
+    ///                     .lock()?;
+    ///    // myfile.lock is removed once filelock is dropped
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn on_drop(mut self, action: OnDrop) -> Self {
+        self.on_drop = action;
+        self
+    }
+
+    /// Once locked, replace the file's contents with a description of this
+    /// process, see [`HolderInfo`](struct.HolderInfo.html)
+    ///
+    /// The file must be opened as [`writeable`](#method.writeable). Others
+    /// can read the description back with
+    /// [`HolderInfo::read`](struct.HolderInfo.html#method.read) to find out
+    /// who holds the lock, even from another machine sharing the
+    /// filesystem. This is meant for dedicated lock files.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FileLock, HolderInfo};
+    ///use std::io::{ErrorKind, Result};
+    ///
+    ///fn main() -> Result<()> {
+    ///    match FileLock::new("myfile.lock")
+    ///        .writeable(true)
+    ///        .record_holder("nightly backup")
+    ///        .lock()
+    ///    {
+    ///        Ok(filelock) => {}
+    ///        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+    ///            if let Some(holder) = HolderInfo::read("myfile.lock")? {
+    ///                eprintln!("held by {} on {}", holder.label, holder.hostname);
+    ///            }
+    ///        }
+    ///        Err(e) => return Err(e),
+    ///    }
+    ///    # let _ = std::fs::remove_file("myfile.lock");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn record_holder(mut self, label: impl Into<String>) -> Self {
+        self.holder_label = Some(label.into());
+        self
+    }
+
+    /// Open the file in append mode
+    ///
+    /// Every write then goes to the end of the file, whatever the position,
+    /// so processes taking turns at a shared log can't overwrite each
+    /// other's entries. Use it with [`writeable(true)`](#method.writeable)
+    /// so writers exclude each other while appending a record.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::new("log.txt")
+    ///                         .blocking(true)
+    ///                         .writeable(true)
+    ///                         .append(true)
+    ///                         .lock()?;
+    ///    writeln!(filelock, "started")?;
+    ///    # let _ = std::fs::remove_file("log.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn append(mut self, v: bool) -> Self {
+        self.append = v;
+        self
+    }
+
+    /// Create the file with `O_CREAT | O_EXCL`, failing with
+    /// `ErrorKind::AlreadyExists` if it exists
+    ///
+    /// Of several processes racing to create the file, only one succeeds,
+    /// for conventions where the file's existence means something. Requires
+    /// [`writeable(true)`](#method.writeable) or
+    /// [`append(true)`](#method.append), see also
+    /// [`FileLock::create_exclusive_new`](struct.FileLock.html#method.create_exclusive_new).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::{ErrorKind, Result};
+    ///
+    ///fn main() -> Result<()> {
+    ///    # let _ = std::fs::remove_file("setup.lock");
+    ///    match FileLock::new("setup.lock").writeable(true).create_new(true).lock() {
+    ///        Ok(filelock) => println!("setting up"),
+    ///        Err(e) if e.kind() == ErrorKind::AlreadyExists => println!("done already"),
+    ///        Err(e) => return Err(e),
+    ///    }
+    ///    # let _ = std::fs::remove_file("setup.lock");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn create_new(mut self, v: bool) -> Self {
+        self.create_new = v;
+        self
+    }
+
+    /// Empty the file once the lock is held
+    ///
+    /// Unlike opening with `O_TRUNC`, this waits until no one else can be
+    /// in the middle of writing the file. Requires
+    /// [`writeable(true)`](#method.writeable).
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut filelock = FileLock::new("state.txt")
+    ///                         .blocking(true)
+    ///                         .writeable(true)
+    ///                         .truncate(true)
+    ///                         .lock()?;
+    ///    filelock.write_all(b"fresh state")?;
+    ///    # let _ = std::fs::remove_file("state.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn truncate(mut self, v: bool) -> Self {
+        self.truncate = v;
+        self
+    }
+
+    /// Whether to create the file if it doesn't exist
+    ///
+    /// Defaults to [`writeable`](#method.writeable). With `create(false)`,
+    /// locking a missing file fails with `ErrorKind::NotFound`.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::ErrorKind;
+    ///
+    ///let result = FileLock::new("missing.txt")
+    ///               .writeable(true)
+    ///               .create(false)
+    ///               .lock();
+    ///assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+    ///```
+    pub fn create(mut self, v: bool) -> Self {
+        self.create = Some(v);
+        self
+    }
+
+    /// Permission bits to create the file with, instead of `0o666`
+    ///
+    /// The process umask still applies, and the mode of an existing file is
+    /// left alone.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("daemon.lock")
+    ///                     .writeable(true)
+    ///                     .mode(0o600)
+    ///                     .lock()?;
+    ///    # let _ = std::fs::remove_file("daemon.lock");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Whether to close the file on `exec()`, as by default
+    ///
+    /// With `cloexec(false)`, programs started with `exec()` inherit the
+    /// file descriptor. Only locks that belong to the open file description,
+    /// i.e. [`ofd`](#method.ofd) and [`flock`](#method.flock) locks, are
+    /// then still held by the new program.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::os::unix::io::AsRawFd;
+    ///use std::process::Command;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("inherited.lock")
+    ///                     .writeable(true)
+    ///                     .flock(true)
+    ///                     .cloexec(false)
+    ///                     .lock()?;
+    ///    Command::new("true")
+    ///        .env("LOCK_FD", filelock.as_raw_fd().to_string())
+    ///        .status()?;
+    ///    # let _ = std::fs::remove_file("inherited.lock");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn cloexec(mut self, v: bool) -> Self {
+        self.cloexec = v;
+        self
+    }
+
+    /// Open the file with `options` instead of read access plus, if
+    /// [`writeable`](#method.writeable), write access and creation
+    ///
+    /// The other settings of how the file is opened, such as
+    /// [`append`](#method.append), are then ignored.
+    ///
+    /// This gives full control over how the file is opened, e.g. appending,
+    /// permissions of a new file or custom flags through `OpenOptionsExt`.
+    /// The lock is still taken as set with `writeable`, which must match the
+    /// access mode: an exclusive lock on a file not opened for writing fails
+    /// with `EBADF`.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::fs::OpenOptions;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let mut options = OpenOptions::new();
+    ///    options.append(true).create(true);
+    ///    let filelock = FileLock::new("log.txt")
+    ///                     .writeable(true)
+    ///                     .open_options(options)
+    ///                     .lock()?;
+    ///    # let _ = std::fs::remove_file("log.txt");
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn open_options(mut self, options: OpenOptions) -> Self {
+        self.open_options = Some(options);
+        self
+    }
+
+    /// Whether the lock will wait for the file to be released
+    pub fn is_blocking(&self) -> bool {
+        self.blocking
+    }
+
+    /// Whether the file will be opened as writeable
+    pub fn is_writeable(&self) -> bool {
+        self.writeable
+    }
+
+    /// Whether the lock will be exclusive, see
+    /// [`exclusive`](#method.exclusive)
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive.unwrap_or(self.writeable)
+    }
+
+    /// The `(start, len)` byte range that will be locked, as set by
+    /// [`range`](#method.range)
+    pub fn byte_range(&self) -> (u64, u64) {
+        self.range
+    }
+
+    /// Create a [`FileLock`](struct.FileLock.html) with these parameters.
+    pub fn lock(self) -> Result<FileLock> {
+        let block = Block::from(self.blocking);
+        self.lock_with(block)
+    }
+
+    /// Like [`lock`](#method.lock), but telling apart why it failed
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::{FileLock, FileLockError};
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    match FileLock::new("myfile.txt").writeable(true).lock_detailed() {
+    ///        Ok(filelock) => println!("got it"),
+    ///        Err(FileLockError::Contended(_)) => println!("busy"),
+    ///        Err(e) => return Err(e.into()),
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn lock_detailed(self) -> std::result::Result<FileLock, FileLockError> {
+        let block = Block::from(self.blocking);
+        self.lock_classified(block)
+    }
+
+    /// Like [`lock`](#method.lock), but waiting for the lock on a helper
+    /// thread so the calling task doesn't block its executor, see
+    /// [`AsyncFileLock`](struct.AsyncFileLock.html)
+    ///
+    /// # Examples
+    ///
+    ///```no_run
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///async fn update() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///        .blocking(true)
+    ///        .writeable(true)
+    ///        .range(0, 64)
+    ///        .lock_async()
+    ///        .await?;
+    ///    Ok(())
+    ///}
+    ///```
+    #[cfg(feature = "async")]
+    pub fn lock_async(self) -> LockFuture
+    where
+        T: Send + 'static,
+    {
+        LockFuture::new(self)
+    }
+
+    /// Lock the file, run `f` on it and release the lock, returning what
+    /// `f` returned
+    ///
+    /// The lock can't outlive the call nor be released before `f` returns,
+    /// and it is released even if `f` fails or panics, as the handle is
+    /// dropped then. Errors from locking, from `f` and from unlocking are
+    /// all returned the same way.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::prelude::*;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let len = FileLock::new("myfile.txt")
+    ///        .blocking(true)
+    ///        .writeable(true)
+    ///        .with_lock(|filelock| {
+    ///            filelock.write_all(b"Hello, World!")?;
+    ///            Ok(filelock.metadata()?.len())
+    ///        })?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn with_lock<R>(
+        self,
+        f: impl FnOnce(&mut FileLock) -> Result<R>,
+    ) -> Result<R> {
+        let mut filelock = self.lock()?;
+        let value = f(&mut filelock)?;
+        filelock.unlock()?;
+        Ok(value)
+    }
+
+    /// Like [`lock`](#method.lock), but never waiting, whatever
+    /// [`blocking`](#method.blocking) says
+    ///
+    /// If another process holds a conflicting lock, this fails with an error
+    /// of kind `ErrorKind::WouldBlock`, whether the kernel reported `EAGAIN`
+    /// or `EACCES`, so contention can be told apart from other errors such
+    /// as `ErrorKind::PermissionDenied` when opening the file.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::{ErrorKind, Result};
+    ///
+    ///fn main() -> Result<()> {
+    ///    match FileLock::new("myfile.txt").writeable(true).try_lock() {
+    ///        Ok(filelock) => println!("got it"),
+    ///        Err(e) if e.kind() == ErrorKind::WouldBlock => println!("busy"),
+    ///        Err(e) => return Err(e),
+    ///    }
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn try_lock(self) -> Result<FileLock> {
+        self.lock_with(Block::No)
+    }
+
+    /// Like [`lock`](#method.lock), but waiting at most `timeout` for the
+    /// file to be released, whatever [`blocking`](#method.blocking) says
+    ///
+    /// The wait happens on a helper thread, interrupted with `SIGURG` once
+    /// `timeout` has passed, as in
+    /// [`FileLock::lock_timeout_thread`](struct.FileLock.html#method.lock_timeout_thread).
+    /// If the file is still held then, an error of kind
+    /// `ErrorKind::TimedOut` is returned and the file is closed.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::time::Duration;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let filelock = FileLock::new("myfile.txt")
+    ///                     .writeable(true)
+    ///                     .lock_timeout(Duration::from_secs(1))?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn lock_timeout(self, timeout: Duration) -> Result<FileLock> {
+        self.lock_with(Block::For(timeout))
+    }
+
+    /// Like [`lock_timeout`](#method.lock_timeout), but giving up at
+    /// `deadline`
+    ///
+    /// A deadline already passed still gets one attempt that doesn't wait.
+    /// The error message tells how long was waited.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///use std::time::{Duration, Instant};
+    ///
+    ///fn main() -> Result<()> {
+    ///    let deadline = Instant::now() + Duration::from_secs(1);
+    ///    let filelock = FileLock::new("myfile.txt").try_lock_until(deadline)?;
+    ///    Ok(())
+    ///}
+    ///```
+    pub fn try_lock_until(
+        self,
+        deadline: std::time::Instant,
+    ) -> Result<FileLock> {
+        let timeout =
+            deadline.saturating_duration_since(std::time::Instant::now());
+        self.lock_timeout(timeout)
+    }
+
+    /// Take over `fd`, which already holds the lock this builder describes
+    ///
+    /// This suits a daemon handed a locked descriptor by its supervisor or
+    /// by the program that `exec()`ed it, see
+    /// [`cloexec`](#method.cloexec). Nothing is locked or checked: the
+    /// [`range`](#method.range), [`exclusive`](#method.exclusive) and the
+    /// kind of lock, e.g. [`ofd`](#method.ofd), merely record what `fd`
+    /// holds, and the path is only used by [`on_drop`](#method.on_drop).
+    /// Only locks that belong to the open file description are inherited
+    /// from another process. Lock files from
+    /// [`link_lockfile`](#method.link_lockfile) can't be taken over and fail
+    /// with `ErrorKind::InvalidInput`, leaving `fd` open.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor that nothing else owns, as it is
+    /// closed when the `FileLock` is dropped.
+    ///
+    /// # Examples
+    ///
+    ///```no_run
+    ///use file_locker::FileLock;
+    ///use std::io::Result;
+    ///
+    ///fn main() -> Result<()> {
+    ///    let fd = std::env::var("LOCK_FD").unwrap().parse().unwrap();
+    ///    let filelock = unsafe {
+    ///        FileLock::new("/run/mydaemon.lock")
+    ///            .writeable(true)
+    ///            .flock(true)
+    ///            .adopt_raw_fd(fd)?
+    ///    };
+    ///    Ok(())
+    ///}
+    ///```
+    pub unsafe fn adopt_raw_fd(self, fd: RawFd) -> Result<FileLock> {
+        if self.link {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a link lock file can't be taken over",
+            ));
+        }
+        let (start, end, lock_type) = self.lock_span()?;
+        let mut filelock = FileLock::unlocked(File::from_raw_fd(fd));
+        filelock.backend = self.backend()?;
+        filelock.on_drop = self.drop_action();
+        filelock.record(&mut filelock.held(), start, end, lock_type);
+        Ok(filelock)
+    }
+
+    fn lock_with(self, block: Block) -> Result<FileLock> {
+        self.lock_classified(block).map_err(Error::from)
+    }
+
+    fn lock_classified(
+        self,
+        block: Block,
+    ) -> std::result::Result<FileLock, FileLockError> {
+        if self.dir.is_some()
+            && (self.open_options.is_some()
+                || self.link
+                || self.on_drop == OnDrop::Delete)
+        {
+            return Err(FileLockError::Io(Error::new(
+                ErrorKind::InvalidInput,
+                "this setting needs a path rather than a directory and name",
+            )));
+        }
+        let own_file = self.writeable && self.is_exclusive();
+        if self.holder_label.is_some() && !own_file {
+            return Err(FileLockError::Io(Error::new(
+                ErrorKind::InvalidInput,
+                "recording the holder needs an exclusive lock on a writeable file",
+            )));
+        }
+        if self.truncate && !own_file {
+            return Err(FileLockError::Io(Error::new(
+                ErrorKind::InvalidInput,
+                "truncating needs an exclusive lock on a writeable file",
+            )));
+        }
+        let filelock = self.lock_current(block)?;
+        if self.truncate {
+            filelock.set_len(0).map_err(FileLockError::Io)?;
+        }
+        if let Some(label) = &self.holder_label {
+            let write = || {
+                let contents = HolderInfo::current(label)?.to_bytes()?;
+                filelock.set_len(0)?;
+                filelock.write_all_at(&contents, 0)?;
+                filelock.sync_data()
+            };
+            write().map_err(FileLockError::Io)?;
+        }
+        Ok(filelock)
+    }
+
+    fn lock_current(
+        &self,
+        block: Block,
+    ) -> std::result::Result<FileLock, FileLockError> {
+        loop {
+            let filelock = self.lock_once(block.clone())?;
+            if self.on_drop != OnDrop::Delete || !self.enabled {
+                return Ok(filelock);
+            }
+            // the holder we waited for may have removed the file before
+            // releasing it, leaving us a lock on an inode nobody else will
+            // open again, so start over with whatever the path names now
+            match same_file(&filelock.file, self.file_path.as_ref()) {
+                Ok(true) => return Ok(filelock),
+                Ok(false) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(FileLockError::Io(e)),
+            }
+        }
+    }
+
+    /// How to open the file
+    fn options(&self) -> OpenOptions {
+        if let Some(options) = &self.open_options {
+            return options.clone();
+        }
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(self.writeable)
+            .create(self.create.unwrap_or(self.writeable))
+            .append(self.append)
+            .create_new(self.create_new);
+        if let Some(mode) = self.mode {
+            options.mode(mode);
+        }
+        options
+    }
+
+    fn open(&self) -> Result<File> {
+        let dir = match &self.dir {
+            Some(dir) => match &**dir {
+                Ok(dir) => dir,
+                Err(e) => return Err(Error::new(e.kind(), e.to_string())),
+            },
+            None => return self.options().open(&self.file_path),
+        };
+        let mut flags = OFlag::O_CLOEXEC;
+        flags |= if self.writeable || self.append {
+            OFlag::O_RDWR
+        } else {
+            OFlag::O_RDONLY
+        };
+        if self.create_new {
+            flags |= OFlag::O_CREAT | OFlag::O_EXCL;
+        } else if self.create.unwrap_or(self.writeable) {
+            flags |= OFlag::O_CREAT;
+        }
+        if self.append {
+            flags |= OFlag::O_APPEND;
+        }
+        // openat() always takes the path relative to the directory, like
+        // OpenOptions does with the working directory, except that a
+        // leading / would escape it
+        let path = self.file_path.as_ref();
+        let path = path.strip_prefix("/").unwrap_or(path);
+        let mode = Mode::from_bits_truncate(self.mode.unwrap_or(0o666) as _);
+        let fd = openat(dir.as_raw_fd(), path, flags, mode).map_err(cver)?;
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    /// The kind of lock to take
+    fn backend(&self) -> Result<Backend> {
+        if !self.enabled {
+            return Ok(Backend::Noop);
+        }
+        if self.link {
+            let link = link::LinkLock::new(self.file_path.as_ref())?;
+            return Ok(Backend::Link(link));
+        }
+        if self.flock {
+            return Ok(Backend::Flock);
+        }
+        #[cfg(target_os = "linux")]
+        if self.ofd {
+            return Ok(Backend::Ofd);
+        }
+        Ok(Backend::Fcntl)
+    }
+
+    fn drop_action(&self) -> DropAction {
+        match self.on_drop {
+            OnDrop::Unlock => DropAction::Unlock,
+            OnDrop::Delete => {
+                DropAction::Delete(self.file_path.as_ref().to_owned())
+            }
+            OnDrop::Close => DropAction::Close,
+        }
+    }
+
+    /// The `start`, `end` and type of the lock to take
+    fn lock_span(&self) -> Result<(u64, Option<u64>, LockType)> {
+        let (start, len) = self.range;
+        let lock_type = if self.is_exclusive() {
+            LockType::Write
+        } else {
+            LockType::Read
+        };
+        Ok((start, range_end(start, len)?, lock_type))
+    }
+
+    fn lock_once(
+        &self,
+        block: Block,
+    ) -> std::result::Result<FileLock, FileLockError> {
+        let file = self.open().map_err(FileLockError::Open)?;
+        if !self.cloexec {
+            // std always opens with O_CLOEXEC
+            fcntl(file.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::empty()))
+                .map_err(|e| FileLockError::Io(cver(e)))?;
+        }
+        if let Some(validate) = &self.validate {
+            validate(&file).map_err(FileLockError::Io)?;
+        }
+        let mut filelock = FileLock::unlocked(file);
+        filelock.backend = self.backend().map_err(FileLockError::Io)?;
+        filelock.on_drop = self.drop_action();
+        let (start, end, lock_type) =
+            self.lock_span().map_err(FileLockError::Io)?;
+        let block = match (self.cancel.clone(), block) {
+            (Some(cancel), Block::Forever(_)) => {
+                Block::Cancellable(cancel, None)
+            }
+            (None, Block::Forever(_)) => Block::Forever(self.on_interrupt),
+            (Some(cancel), Block::For(timeout)) => {
+                Block::Cancellable(cancel, Some(timeout))
+            }
+            (_, block) => block,
+        };
+
+        let mut attempt = 0;
+        loop {
+            match filelock.set_lock(lock_type, start, end, block.clone()) {
+                Err(e)
+                    if matches!(block, Block::No)
+                        && attempt < self.retries
+                        && e.kind() == ErrorKind::WouldBlock =>
+                {
+                    std::thread::sleep(self.backoff.delay(attempt));
+                    attempt += 1;
+                }
+                result => {
+                    return result
+                        .map(|_| filelock)
+                        .map_err(FileLockError::from_lock)
+                }
+            }
+        }
+    }
+}
+
+/// How long to wait between the retries set with
+/// [`FileLockBuilder::retries`](struct.FileLockBuilder.html#method.retries)
+///
+/// Each delay is randomly shortened by up to half, so processes that
+/// collided once don't keep retrying in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// The same delay before every retry
+    Fixed(Duration),
+    /// `initial` before the first retry, doubling each time up to `max`
+    Exponential {
+        /// delay before the first retry
+        initial: Duration,
+        /// longest delay
+        max: Duration,
+    },
+}
+
+impl Default for Backoff {
+    /// Exponential from 10 ms up to 1 s
+    fn default() -> Self {
+        Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before retry number `attempt`, counting from 0
+    fn delay(self, attempt: u32) -> Duration {
+        let delay = match self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(1 << attempt.min(31))
+                .map_or(max, |delay| delay.min(max)),
+        };
+        delay.mul_f64(0.5 + jitter() / 2.0)
+    }
+}
+
+/// A number in `[0, 1)`, random enough to spread retries apart
+fn jitter() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos()),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        match &self.on_drop {
+            DropAction::Unlock => {}
+            DropAction::Delete(path) => {
+                let exclusive = self
+                    .held()
+                    .iter()
+                    .any(|range| range.lock_type == LockType::Write);
+                if exclusive && matches!(same_file(&self.file, path), Ok(true))
+                {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+            DropAction::Close => {
+                #[cfg(feature = "leak-check")]
+                leak::forget(self.file.as_raw_fd());
+                return;
+            }
+        }
+        if self.is_locked() {
+            let _ = self.unlock();
+        }
+        #[cfg(feature = "leak-check")]
+        leak::forget(self.file.as_raw_fd());
+    }
+}
+
+/// A buffered writer over a [`FileLock`](struct.FileLock.html), made with
+/// [`FileLock::into_buf_writer`](struct.FileLock.html#method.into_buf_writer)
+///
+/// Whatever is still buffered is always written out before the lock is
+/// released, so no other process can see the file without it. Dropping the
+/// writer flushes and then unlocks but has to ignore errors; call
+/// [`close`](#method.close) to find out whether everything was written.
+///
+/// # Examples
+///
+///```
+///use file_locker::FileLock;
+///use std::io::prelude::*;
+///use std::io::Result;
+///
+///fn main() -> Result<()> {
+///    let mut writer = FileLock::lock_exclusive("log.txt")?.into_buf_writer();
+///    for i in 0..100 {
+///        writeln!(writer, "line {}", i)?;
+///    }
+///    writer.close()?;
+///    # let _ = std::fs::remove_file("log.txt");
+///    Ok(())
+///}
+///```
+#[derive(Debug)]
+pub struct LockedBufWriter {
+    // `BufWriter` flushes on drop before dropping, and so unlocking, the
+    // `FileLock`
+    writer: BufWriter<FileLock>,
+}
+
+impl LockedBufWriter {
+    /// The underlying lock
+    pub fn get_ref(&self) -> &FileLock {
+        self.writer.get_ref()
+    }
+
+    /// Flush the buffer and give back the lock, still held
+    ///
+    /// If flushing fails the lock is released, dropping whatever couldn't be
+    /// written, and the flush error is returned.
+    pub fn into_inner(self) -> Result<FileLock> {
+        self.writer.into_inner().map_err(|e| {
+            let (error, writer) = e.into_parts();
+            let (filelock, _unwritten) = writer.into_parts();
+            let _ = filelock.unlock();
+            error
+        })
+    }
+
+    /// Flush the buffer, then release the lock
+    ///
+    /// If flushing fails the lock is still released, dropping whatever
+    /// couldn't be written, and the flush error is returned.
+    pub fn close(self) -> Result<()> {
+        self.into_inner()?.unlock()
+    }
+}
+
+impl Write for LockedBufWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        self.writer.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// A lock on a descriptor owned by someone else, made with
+/// [`FileLock::lock_fd`](struct.FileLock.html#method.lock_fd)
+///
+/// It derefs to a [`FileLock`](struct.FileLock.html) to lock, release or
+/// inspect ranges, and releases whatever it holds when dropped. It never
+/// closes the descriptor, so it only gives shared access to the `File`,
+/// which can be read and written through `file()` as `&File` implements
+/// `Read` and `Write`.
+#[derive(Debug)]
+pub struct FdLock<'fd> {
+    // never dropped: its `File` doesn't own the descriptor
+    filelock: std::mem::ManuallyDrop<FileLock>,
+    fd: std::marker::PhantomData<BorrowedFd<'fd>>,
+}
+
+impl<'fd> FdLock<'fd> {
+    fn new(fd: BorrowedFd<'fd>) -> Self {
+        // Safety: the `File` is turned back into a raw descriptor instead of
+        // being closed, see `Drop`, and `'fd` keeps the descriptor open
+        // until then
+        let file = unsafe { File::from_raw_fd(fd.as_raw_fd()) };
+        FdLock {
+            filelock: std::mem::ManuallyDrop::new(FileLock::unlocked(file)),
+            fd: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Deref for FdLock<'_> {
+    type Target = FileLock;
+
+    fn deref(&self) -> &FileLock {
+        &self.filelock
+    }
+}
+
+impl AsFd for FdLock<'_> {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.filelock.as_fd()
+    }
+}
+
+impl Drop for FdLock<'_> {
+    fn drop(&mut self) {
+        if self.filelock.is_locked() {
+            let _ = self.filelock.unlock();
+        }
+        // Safety: `filelock` is never used again
+        let filelock =
+            unsafe { std::mem::ManuallyDrop::take(&mut self.filelock) };
+        let _ = filelock.into_file().into_raw_fd();
+    }
+}
+
+/// A [`FileLock`](struct.FileLock.html) with cleanup actions that run before
+/// it is released
+///
+/// Struct fields are dropped in declaration order, so a struct holding a
+/// lock next to, say, a buffered writer may release the lock before the
+/// buffer is flushed. A `LockBundle` owns the lock and runs the registered
+/// actions, in registration order, before unlocking, whether it is dropped
+/// or explicitly [`release`](#method.release)d. Unlocking is always the
+/// last thing that happens, even if an action fails.
+///
+/// # Examples
+///
+///```
+///use file_locker::{FileLock, LockBundle};
+///use std::io::prelude::*;
+///use std::io::Result;
+///
+///fn main() -> Result<()> {
+///    let filelock = FileLock::lock_exclusive("myfile.txt")?;
+///    let mut bundle = LockBundle::new(filelock)
+///        .before_unlock(|filelock| filelock.write_all(b"footer\n"))
+///        .before_unlock(|filelock| filelock.sync_all());
+///
+///    bundle.write_all(b"Hello, world\n")?;
+///    // writes the footer, syncs, then unlocks
+///    bundle.release()
+///}
+///```
+pub struct LockBundle {
+    // `None` only once released
+    filelock: Option<FileLock>,
+    cleanups: Vec<Cleanup>,
+}
+
+type Cleanup = Box<dyn FnOnce(&mut FileLock) -> Result<()> + Send>;
+
+impl LockBundle {
+    /// Bundle `filelock` with no cleanup actions yet
+    pub fn new(filelock: FileLock) -> LockBundle {
+        LockBundle {
+            filelock: Some(filelock),
+            cleanups: Vec::new(),
+        }
+    }
+
+    /// Register `cleanup` to run before the lock is released, after the
+    /// actions registered so far
+    pub fn before_unlock<F>(mut self, cleanup: F) -> Self
+    where
+        F: FnOnce(&mut FileLock) -> Result<()> + Send + 'static,
+    {
+        self.cleanups.push(Box::new(cleanup));
+        self
+    }
+
+    /// Run the cleanup actions, then release the lock
+    ///
+    /// Every action runs even if an earlier one fails. The first error,
+    /// from an action or from unlocking, is returned.
+    pub fn release(mut self) -> Result<()> {
+        self.run()
+    }
+
+    fn run(&mut self) -> Result<()> {
+        let mut filelock = match self.filelock.take() {
+            Some(filelock) => filelock,
+            None => return Ok(()),
+        };
+        let mut result = Ok(());
+        for cleanup in self.cleanups.drain(..) {
+            let cleaned = cleanup(&mut filelock);
+            result = result.and(cleaned);
+        }
+        result.and(filelock.unlock())
+    }
+}
+
+impl fmt::Debug for LockBundle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockBundle")
+            .field("filelock", &self.filelock)
+            .field("cleanups", &self.cleanups.len())
+            .finish()
+    }
+}
+
+impl Deref for LockBundle {
+    type Target = FileLock;
+
+    fn deref(&self) -> &FileLock {
+        self.filelock
+            .as_ref()
+            .expect("lock bundle already released")
+    }
+}
+
+impl DerefMut for LockBundle {
+    fn deref_mut(&mut self) -> &mut FileLock {
+        self.filelock
+            .as_mut()
+            .expect("lock bundle already released")
+    }
+}
+
+impl Drop for LockBundle {
+    fn drop(&mut self) {
+        let _ = self.run();
+    }
+}
+
+/// Whether `flock` covers the whole file
+fn whole_file(flock: &libc::flock) -> bool {
+    (flock.l_whence, flock.l_start, flock.l_len)
+        == (libc::SEEK_SET as libc::c_short, 0, 0)
+}
+
+/// Whether `path` still names the file open as `file`
+fn same_file(file: &File, path: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let (open, named) = (file.metadata()?, std::fs::metadata(path)?);
+    Ok((open.dev(), open.ino()) == (named.dev(), named.ino()))
+}
+
+fn open(file_path: impl AsRef<Path>, writeable: bool) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(writeable)
+        .create(writeable)
+        .open(file_path)
+}
+
+/// List the `(start, len)` data extents of a possibly sparse file
+#[cfg(any(target_os = "linux", target_os = "freebsd"))]
+fn data_regions(file: &File) -> Result<Vec<(u64, u64)>> {
+    use nix::unistd::{lseek, Whence};
+
+    let fd = file.as_raw_fd();
+    let mut regions = Vec::new();
+    let mut pos = 0;
+    loop {
+        let start = match lseek(fd, pos, Whence::SeekData) {
+            Ok(start) => start,
+            // no data past pos
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(cver(e)),
+        };
+        let end = lseek(fd, start, Whence::SeekHole).map_err(cver)?;
+        regions.push((start as u64, (end - start) as u64));
+        pos = end;
+    }
+    lseek(fd, 0, Whence::SeekSet).map_err(cver)?;
+    Ok(regions)
+}
+
+/// Overwrite the whole file with `contents` and sync it to disk
+fn replace_contents(file: &mut File, contents: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.set_len(0)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+fn new_flock(
+    l_type: libc::c_int,
+    start: libc::off_t,
+    len: libc::off_t,
+) -> libc::flock {
+    libc::flock {
+        l_type: l_type as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: start,
+        l_len: len,
+        l_pid: 0,
+        #[cfg(target_os = "freebsd")]
+        l_sysid: 0,
+    }
+}
+
+/// The `flock()` operation equivalent to the lock type of `flock`
+fn flock_arg(flock: &libc::flock, blocking: bool) -> FlockArg {
+    match (LockType::from_raw(flock.l_type), blocking) {
+        (LockType::Unlocked, _) => FlockArg::Unlock,
+        (LockType::Read, true) => FlockArg::LockShared,
+        (LockType::Read, false) => FlockArg::LockSharedNonblock,
+        (LockType::Write, true) => FlockArg::LockExclusive,
+        (LockType::Write, false) => FlockArg::LockExclusiveNonblock,
+    }
+}
+
+/// Build the `flock` for a lock request, checking it the way the kernel
+/// would but without any syscall
+///
+/// `whence` must be `SEEK_SET`, `SEEK_CUR` or `SEEK_END`. A negative `len`
+/// covers the `-len` bytes before `start`, and a `len` of 0 everything from
+/// `start` onwards. Fails with `ErrorKind::InvalidInput` if the range would
+/// start before offset 0 (only checked for `SEEK_SET`, as the other bases
+/// are only known to the kernel) or end past the largest `off_t`.
+pub(crate) fn build_flock(
+    lock_type: LockType,
+    whence: libc::c_int,
+    start: libc::off_t,
+    len: libc::off_t,
+) -> Result<libc::flock> {
+    let invalid = |msg| Err(Error::new(ErrorKind::InvalidInput, msg));
+    if ![libc::SEEK_SET, libc::SEEK_CUR, libc::SEEK_END].contains(&whence) {
+        return invalid("invalid whence");
+    }
+    let first = match len {
+        len if len < 0 => start.checked_add(len),
+        _ => Some(start),
+    };
+    let last = match len {
+        len if len > 0 => start.checked_add(len - 1),
+        len if len < 0 => start.checked_sub(1),
+        _ => Some(start),
+    };
+    match (first, last) {
+        (Some(first), Some(_)) if whence != libc::SEEK_SET || first >= 0 => {}
+        (Some(_), Some(_)) => return invalid("range starts before offset 0"),
+        _ => return invalid("range overflows"),
+    }
+
+    let mut flock = new_flock(lock_type.to_raw(), start, len);
+    flock.l_whence = whence as libc::c_short;
+    Ok(flock)
+}
+
+fn range_flock(
+    lock_type: LockType,
+    start: u64,
+    end: Option<u64>,
+) -> Result<libc::flock> {
+    let len = match end {
+        Some(end) => to_off(end - start)?,
+        None => 0,
+    };
+    build_flock(lock_type, libc::SEEK_SET, to_off(start)?, len)
+}
+
+fn range_end(start: u64, len: u64) -> Result<Option<u64>> {
+    match len {
+        0 => Ok(None),
+        len => match start.checked_add(len) {
+            Some(end) => Ok(Some(end)),
+            None => Err(Error::new(ErrorKind::InvalidInput, "range overflows")),
+        },
+    }
+}
+
+fn to_off(v: u64) -> Result<libc::off_t> {
+    if v > libc::off_t::MAX as u64 {
+        return Err(Error::new(ErrorKind::InvalidInput, "offset out of range"));
+    }
+    Ok(v as libc::off_t)
+}
+
+/// A lock held by another process on any part of `file` that conflicts
+/// with a whole-file lock of `lock_type`, as reported by `F_GETLK`
+fn conflicting_lock(
+    file: &File,
+    lock_type: LockType,
+) -> Result<Option<libc::flock>> {
+    let mut flock = range_flock(lock_type, 0, None)?;
+    fcntl(file.as_raw_fd(), FcntlArg::F_GETLK(&mut flock)).map_err(cver)?;
+    if flock.l_type as libc::c_int == libc::F_UNLCK {
+        return Ok(None);
+    }
+    Ok(Some(flock))
+}
+
+/// Find the lowest starting lock held by another process that would
+/// conflict with a write lock on `[start, end)`
+///
+/// `F_GETLK` reports an arbitrary conflicting lock, so the probe window is
+/// narrowed until nothing starts before the one found.
+fn first_conflict(
+    file: &File,
+    start: u64,
+    end: Option<u64>,
+) -> Result<Option<libc::flock>> {
+    let mut found = None;
+    let mut end = end;
+    loop {
+        let mut flock = range_flock(LockType::Write, start, end)?;
+        fcntl(file.as_raw_fd(), FcntlArg::F_GETLK(&mut flock)).map_err(cver)?;
+        if flock.l_type as libc::c_int == libc::F_UNLCK {
+            return Ok(found);
+        }
+        let lock_start = flock.l_start as u64;
+        found = Some(flock);
+        if lock_start <= start {
+            return Ok(found);
+        }
+        end = Some(lock_start);
+    }
+}
+
+/// Best guess at the path of the file open as `fd`
+#[cfg(any(feature = "events", feature = "leak-check"))]
+fn fd_path(fd: RawFd) -> PathBuf {
+    let link = PathBuf::from(format!("/dev/fd/{}", fd));
+    std::fs::read_link(format!("/proc/self/fd/{}", fd)).unwrap_or(link)
+}
+
+fn cver(e: nix::Error) -> Error {
+    Error::from_raw_os_error(e as i32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::ForkResult::{Child, Parent};
+    use nix::unistd::{fork, Pid};
+    use std::fs::remove_file;
+    use std::os::unix::net::UnixStream;
+    use std::process;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// A forked child keeping whatever `hold` set up alive until dropped
+    struct Holder {
+        child: Pid,
+        release: UnixStream,
+    }
+
+    impl Drop for Holder {
+        fn drop(&mut self) {
+            let _ = self.release.shutdown(std::net::Shutdown::Both);
+            let _ = waitpid(self.child, None);
+        }
+    }
+
+    /// Run `f` in a forked child, typically to take locks that compete with
+    /// the test process, and wait until it has returned
+    fn hold<T>(f: impl FnOnce() -> T) -> Holder {
+        let (mut parent, mut child) = UnixStream::pair().unwrap();
+        match unsafe { fork() } {
+            Ok(Parent { child: pid }) => {
+                drop(child);
+                parent.read_exact(&mut [0]).unwrap();
+                Holder {
+                    child: pid,
+                    release: parent,
+                }
+            }
+            Ok(Child) => {
+                drop(parent);
+                let held = f();
+                let _ = child.write_all(&[0]);
+                let _ = child.read(&mut [0]);
+                drop(held);
+                process::exit(0);
+            }
+            Err(_) => panic!("Error forking tests :("),
+        }
+    }
+
+    /// Run `f` in a forked child, e.g. to look at our locks from the outside,
+    /// and report whether it returned true
+    fn in_child(f: impl FnOnce() -> bool) -> bool {
+        match unsafe { fork() } {
+            Ok(Parent { child }) => {
+                waitpid(child, None) == Ok(WaitStatus::Exited(child, 0))
+            }
+            Ok(Child) => process::exit(if f() { 0 } else { 1 }),
+            Err(_) => panic!("Error forking tests :("),
+        }
+    }
+
+    fn seg(
+        start: u64,
+        len: u64,
+        lock_type: LockType,
+        pid: Option<libc::pid_t>,
+    ) -> LockSegment {
+        LockSegment {
+            start,
+            len,
+            lock_type,
+            pid,
+        }
+    }
+
+    /// Take a raw record lock on `file`, bypassing `FileLock`
+    fn raw_lock(file: &File, l_type: libc::c_int, start: i64, len: i64) {
+        let flock = new_flock(l_type, start, len);
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&flock)).unwrap();
+    }
+
+    #[test]
+    #[rustfmt::skip]
+    #[allow(
+        deprecated,
+        clippy::assign_op_pattern,
+        clippy::bool_comparison,
+        clippy::needless_borrows_for_generic_args,
+        clippy::single_match,
+        clippy::suspicious_open_options
+    )]
+    fn lock_and_unlock() {
+        let filename = "filelock.test";
+
+        for already_exists in &[true, false] {
+            for already_locked in &[true, false] {
+                for already_writable in &[true, false] {
+                    for is_blocking in &[true, false] {
+                        for is_writable in &[true, false] {
+                            if !*already_exists
+                                && (*already_locked || *already_writable)
+                            {
+                                // nonsensical tests
+                                continue;
+                            }
+
+                            let _ = remove_file(&filename);
+
+                            let parent_lock = match *already_exists {
+                                false => None,
+                                true => {
+                                    let _ = OpenOptions::new()
+                                        .write(true)
+                                        .create(true)
+                                        .open(&filename);
+
+                                    match *already_locked {
+                                        false => None,
+                                        true => {
+                                            match FileLock::lock(&filename, true, *already_writable)
+                                            {
+                                                Ok(lock) => Some(lock),
+                                                Err(err) => {
+                                                    panic!("Error creating parent lock ({})", err)
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            };
+
+                            match unsafe { fork() } {
+                                Ok(Parent { child: _ }) => {
+                                    sleep(Duration::from_millis(150));
+
+                                    match parent_lock {
+                                        Some(lock) => {
+                                            let _ = lock.unlock();
+                                        }
+                                        None => {}
+                                    }
+
+                                    sleep(Duration::from_millis(350));
+                                }
+                                Ok(Child) => {
+                                    let mut try_count = 0;
+                                    let mut locked = false;
+
+                                    match *already_locked {
+                                        true => match *is_blocking {
+                                            true => {
+                                                match FileLock::lock(filename, *is_blocking, *is_writable) {
+                                                    Ok(_)  => { locked = true },
+                                                    Err(_) => panic!("Error getting lock after wating for release"),
+                                                }
+                                            }
+                                            false => {
+                                                for _ in 0..5 {
+                                                    match FileLock::lock(
+                                                        filename,
+                                                        *is_blocking,
+                                                        *is_writable,
+                                                    ) {
+                                                        Ok(_) => {
+                                                            locked = true;
+                                                            break;
+                                                        }
+                                                        Err(_) => {
+                                                            sleep(Duration::from_millis(50));
+                                                            try_count = try_count + 1;
+                                                        }
+                                                    }
+                                                }
+                                            }
+                                        },
+                                        false => match FileLock::lock(
+                                            filename,
+                                            *is_blocking,
+                                            *is_writable,
+                                        ) {
+                                            Ok(_) => locked = true,
+                                            Err(_) => match !*already_exists && !*is_writable {
+                                                true => {}
+                                                false => {
+                                                    panic!("Error getting lock with no competition")
+                                                }
+                                            },
+                                        },
+                                    }
+
+                                    match !*already_exists && !is_writable {
+                                        true => assert!(
+                                            locked == false,
+                                            "Locking a non-existent file for reading should fail"
+                                        ),
+                                        false => assert!(
+                                            locked == true,
+                                            "Lock should have been successful"
+                                        ),
+                                    }
+
+                                    match *is_blocking {
+                                        true  => assert!(try_count == 0, "Try count should be zero when blocking"),
+                                        false => {
+                                            match *already_locked {
+                                                false => assert!(try_count == 0, "Try count should be zero when no competition"),
+                                                true  => match !*already_writable && !is_writable {
+                                                    true  => assert!(try_count == 0, "Read lock when locked for reading should succeed first go"),
+                                                    false => assert!(try_count >= 3, "Try count should be >= 3"),
+                                                },
+                                            }
+                                        },
+                                    }
+
+                                    process::exit(7);
+                                }
+                                Err(_) => {
+                                    panic!("Error forking tests :(");
+                                }
+                            }
+
+                            let _ = remove_file(&filename);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn increment_counter() {
+        for format in &[CounterFormat::Text, CounterFormat::Binary] {
+            let filename = format!("counter-{:?}.test", format);
+            let _ = remove_file(&filename);
+
+            let mut children = Vec::new();
+            for _ in 0..4 {
+                match unsafe { fork() } {
+                    Ok(Parent { child }) => children.push(child),
+                    Ok(Child) => {
+                        let ok = (0..25).all(|_| {
+                            FileLock::increment_counter(&filename, 1, *format)
+                                .is_ok()
+                        });
+                        process::exit(if ok { 0 } else { 1 });
+                    }
+                    Err(_) => panic!("Error forking tests :("),
+                }
+            }
+            for child in children {
+                assert_eq!(
+                    waitpid(child, None),
+                    Ok(WaitStatus::Exited(child, 0))
+                );
+            }
+
+            assert_eq!(
+                FileLock::increment_counter(&filename, 0, *format).unwrap(),
+                100
+            );
+            let _ = remove_file(filename);
+        }
+    }
+
+    #[test]
+    fn lock_map() {
+        let filename = "lockmap.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, [0; 64]).unwrap();
+
+        let holder = hold(|| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(filename)
+                .unwrap();
+            raw_lock(&file, libc::F_RDLCK, 40, 10);
+            raw_lock(&file, libc::F_WRLCK, 10, 10);
+            file
+        });
+        let pid = Some(holder.child.as_raw());
+
+        assert_eq!(
+            FileLock::lock_map(filename, 0, 0).unwrap(),
+            vec![
+                seg(0, 10, LockType::Unlocked, None),
+                seg(10, 10, LockType::Write, pid),
+                seg(20, 20, LockType::Unlocked, None),
+                seg(40, 10, LockType::Read, pid),
+                seg(50, 0, LockType::Unlocked, None),
+            ]
+        );
+        assert_eq!(
+            FileLock::lock_map(filename, 15, 30).unwrap(),
+            vec![
+                seg(15, 5, LockType::Write, pid),
+                seg(20, 20, LockType::Unlocked, None),
+                seg(40, 5, LockType::Read, pid),
+            ]
+        );
+
+        drop(holder);
+        assert_eq!(
+            FileLock::lock_map(filename, 0, 0).unwrap(),
+            vec![seg(0, 0, LockType::Unlocked, None)]
+        );
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn singleton() {
+        let filename = "singleton.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::singleton(filename).unwrap());
+        match FileLock::singleton(filename).unwrap() {
+            SingletonResult::AlreadyRunning { pid } => {
+                assert_eq!(pid, Some(holder.child.as_raw()))
+            }
+            SingletonResult::Acquired(_) => panic!("Lock should be held"),
+        }
+
+        drop(holder);
+        match FileLock::singleton(filename).unwrap() {
+            SingletonResult::Acquired(_lock) => assert_eq!(
+                std::fs::read_to_string(filename).unwrap(),
+                format!("{}\n", process::id())
+            ),
+            SingletonResult::AlreadyRunning { .. } => {
+                panic!("Lock should have been released")
+            }
+        }
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn downgrade_then_unlock_range() {
+        let filename = "downgrade.test";
+        let _ = remove_file(filename);
+        let pid = Some(process::id() as libc::pid_t);
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .range(0, 100)
+            .lock()
+            .unwrap();
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 100).unwrap()
+                == vec![seg(0, 100, LockType::Write, pid)]
+        }));
+
+        filelock.downgrade().unwrap();
+        filelock.unlock_range(50, 50).unwrap();
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 100).unwrap()
+                == vec![
+                    seg(0, 50, LockType::Read, pid),
+                    seg(50, 50, LockType::Unlocked, None),
+                ]
+        }));
+
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_timeout_thread() {
+        let filename = "timeout-thread.test";
+        let _ = remove_file(filename);
+
+        let holder =
+            hold(|| FileLock::new(filename).writeable(true).lock().unwrap());
+        let started = std::time::Instant::now();
+        let err = FileLock::lock_timeout_thread(
+            filename,
+            true,
+            Duration::from_millis(200),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        // an ignored wake signal stays ignored, and the wait still ends
+        assert!(in_child(|| {
+            use nix::sys::signal::{signal, SigHandler, Signal};
+
+            unsafe { signal(Signal::SIGURG, SigHandler::SigIgn) }.unwrap();
+            let err = FileLock::lock_timeout_thread(
+                filename,
+                true,
+                Duration::from_millis(200),
+            )
+            .unwrap_err();
+            let old = unsafe { signal(Signal::SIGURG, SigHandler::SigDfl) };
+            err.kind() == ErrorKind::TimedOut
+                && old.unwrap() == SigHandler::SigIgn
+        }));
+
+        drop(holder);
+        FileLock::lock_timeout_thread(
+            filename,
+            true,
+            Duration::from_millis(200),
+        )
+        .unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_ranges_nonblocking() {
+        let filename = "ranges-nonblocking.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, [0; 64]).unwrap();
+        let ranges = [(0, 5), (10, 5), (30, 5)];
+
+        let holder = hold(|| {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(filename)
+                .unwrap();
+            raw_lock(&file, libc::F_WRLCK, 12, 4);
+            file
+        });
+        let err = FileLock::lock_ranges_nonblocking(filename, true, &ranges)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(err.to_string().starts_with("range 1 "));
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 10).unwrap()
+                == vec![seg(0, 10, LockType::Unlocked, None)]
+        }));
+
+        drop(holder);
+        let pid = Some(process::id() as libc::pid_t);
+        let _filelock =
+            FileLock::lock_ranges_nonblocking(filename, true, &ranges).unwrap();
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 35).unwrap()
+                == vec![
+                    seg(0, 5, LockType::Write, pid),
+                    seg(5, 5, LockType::Unlocked, None),
+                    seg(10, 5, LockType::Write, pid),
+                    seg(15, 15, LockType::Unlocked, None),
+                    seg(30, 5, LockType::Write, pid),
+                ]
+        }));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_data_regions() {
+        let filename = "data-regions.test";
+        let _ = remove_file(filename);
+        let file = File::create(filename).unwrap();
+        file.write_all_at(&[1; 4096], 0).unwrap();
+        file.write_all_at(&[1; 4096], 1 << 20).unwrap();
+        let len = file.metadata().unwrap().len();
+        let regions = data_regions(&file).unwrap();
+        assert_eq!(regions.first().map(|r| r.0), Some(0));
+        assert_eq!(regions.last().map(|r| r.0 + r.1), Some(len));
+
+        let pid = Some(process::id() as libc::pid_t);
+        let _filelock = FileLock::lock_data_regions(filename, false).unwrap();
+        assert!(in_child(|| {
+            let mut expected = Vec::new();
+            let mut pos = 0;
+            for &(start, len) in &regions {
+                if start > pos {
+                    expected.push(seg(
+                        pos,
+                        start - pos,
+                        LockType::Unlocked,
+                        None,
+                    ));
+                }
+                expected.push(seg(start, len, LockType::Read, pid));
+                pos = start + len;
+            }
+            expected.push(seg(pos, 0, LockType::Unlocked, None));
+            FileLock::lock_map(filename, 0, 0).unwrap() == expected
+        }));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn transactional_rewrite() {
+        let filename = "rewrite.test";
+        std::fs::write(filename, b"old").unwrap();
+
+        let err = FileLock::transactional_rewrite(filename, |current| {
+            assert_eq!(current, b"old");
+            Err(Error::new(ErrorKind::InvalidData, "bad contents"))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(std::fs::read(filename).unwrap(), b"old");
+
+        FileLock::transactional_rewrite(filename, |_| Ok(b"new".to_vec()))
+            .unwrap();
+        assert_eq!(std::fs::read(filename).unwrap(), b"new");
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn verify_enforcement() {
+        let filename = "enforcement.test";
+        assert!(FileLock::verify_enforcement(filename).unwrap());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn blocking_io() {
+        let filename = "blocking-io.test";
+        let filelock = FileLock::new(filename).writeable(true).lock().unwrap();
+        assert!(filelock.blocking_io().unwrap());
+
+        let flags = nix::fcntl::OFlag::O_RDWR | nix::fcntl::OFlag::O_NONBLOCK;
+        fcntl(filelock.as_raw_fd(), FcntlArg::F_SETFL(flags)).unwrap();
+        assert!(!filelock.blocking_io().unwrap());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_survives_setuid() {
+        if !nix::unistd::geteuid().is_root() {
+            return;
+        }
+        let filename = "setuid.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, [0; 64]).unwrap();
+        std::fs::set_permissions(
+            filename,
+            std::os::unix::fs::PermissionsExt::from_mode(0o600),
+        )
+        .unwrap();
+
+        let holder = hold(|| {
+            let filelock = FileLock::new(filename)
+                .writeable(true)
+                .range(0, 10)
+                .lock()
+                .unwrap();
+            nix::unistd::setuid(nix::unistd::Uid::from_raw(65534)).unwrap();
+            assert!(open(filename, false).is_err());
+            filelock.lock_range(20, 10, false, true).unwrap();
+            filelock
+        });
+        let pid = Some(holder.child.as_raw());
+        assert_eq!(
+            FileLock::lock_map(filename, 0, 30).unwrap(),
+            vec![
+                seg(0, 10, LockType::Write, pid),
+                seg(10, 10, LockType::Unlocked, None),
+                seg(20, 10, LockType::Write, pid),
+            ]
+        );
+        drop(holder);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn scan_dir() {
+        let dir = Path::new("scan-dir.test");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("free"), b"").unwrap();
+
+        let holder =
+            hold(|| FileLock::new(dir.join("held")).writeable(true).lock());
+        let results = FileLock::scan_dir(dir).unwrap();
+        let results: Vec<_> = results
+            .into_iter()
+            .map(|(path, info)| (path, info.unwrap()))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                (dir.join("free"), None),
+                (
+                    dir.join("held"),
+                    Some(LockInfo {
+                        start: 0,
+                        len: 0,
+                        lock_type: LockType::Write,
+                        pid: holder.child.as_raw(),
+                    })
+                ),
+            ]
+        );
+        drop(holder);
+
+        // probing closes the file, releasing this process' own lock on it
+        let path = dir.join("held");
+        let filelock = FileLock::new(&path).writeable(true).lock().unwrap();
+        let results = FileLock::scan_dir(dir).unwrap();
+        assert!(matches!(results[1], (ref p, Ok(None)) if *p == path));
+        assert!(in_child(|| FileLock::new(&path)
+            .writeable(true)
+            .lock()
+            .is_ok()));
+        drop(filelock);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn lock_growing() {
+        let filename = "growing.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, [0; 10]).unwrap();
+
+        let holder = hold(|| FileLock::lock_growing(filename).unwrap());
+        let reader = FileLock::new(filename).range(0, 1).lock().unwrap();
+        assert!(in_child(|| {
+            FileLock::new(filename).range(10, 1).lock().is_err()
+        }));
+        assert_eq!(
+            FileLock::lock_map(filename, 0, 0).unwrap(),
+            vec![
+                seg(0, 10, LockType::Unlocked, None),
+                seg(10, 0, LockType::Write, Some(holder.child.as_raw())),
+            ]
+        );
+        drop(reader);
+        drop(holder);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_ranges_deadlock() {
+        let filename = "ranges-deadlock.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, [0; 64]).unwrap();
+        let first = FileLock::new(filename)
+            .writeable(true)
+            .range(0, 10)
+            .lock()
+            .unwrap();
+
+        // the child holds [10, 20) then waits for our [0, 10)
+        let (mut parent, mut child) = UnixStream::pair().unwrap();
+        let pid = match unsafe { fork() } {
+            Ok(Parent { child: pid }) => pid,
+            Ok(Child) => {
+                let filelock = FileLock::new(filename)
+                    .writeable(true)
+                    .range(10, 10)
+                    .lock()
+                    .unwrap();
+                let _ = child.write_all(&[0]);
+                let ok = filelock.lock_range(0, 10, true, true).is_ok();
+                process::exit(if ok { 0 } else { 1 });
+            }
+            Err(_) => panic!("Error forking tests :("),
+        };
+        parent.read_exact(&mut [0]).unwrap();
+        sleep(Duration::from_millis(200));
+
+        let err =
+            FileLock::lock_ranges(filename, true, true, &[(20, 10), (10, 10)])
+                .unwrap_err();
+        assert_eq!(err.kind(), Error::from_raw_os_error(libc::EDEADLK).kind());
+        assert!(err.to_string().starts_with("range 1 "));
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 20, 10).unwrap()
+                == vec![seg(20, 10, LockType::Unlocked, None)]
+        }));
+
+        drop(first);
+        assert_eq!(waitpid(pid, None), Ok(WaitStatus::Exited(pid, 0)));
+        let _ = remove_file(filename);
+    }
+
+    /// Downstream code relies on these exact signatures, refactors must keep
+    /// them compiling
+    #[test]
+    #[allow(deprecated)]
+    fn compat_signatures() {
+        let _: fn(&'static str) -> FileLockBuilder<&'static str> =
+            FileLock::new;
+        let _: fn(&'static str, bool, bool) -> Result<FileLock> =
+            FileLock::lock;
+        let _: fn(&FileLock) -> Result<()> = FileLock::unlock;
+        let _: fn(
+            FileLockBuilder<&'static str>,
+            bool,
+        ) -> FileLockBuilder<&'static str> = FileLockBuilder::blocking;
+        let _: fn(
+            FileLockBuilder<&'static str>,
+            bool,
+        ) -> FileLockBuilder<&'static str> = FileLockBuilder::writeable;
+        let _: fn(FileLockBuilder<&'static str>) -> Result<FileLock> =
+            FileLockBuilder::lock;
+
+        let filename = "compat.test";
+        let filelock = FileLock::lock(filename, false, true).unwrap();
+        filelock.unlock().unwrap();
+        drop(filelock);
+        let filelock = FileLock::lock(filename, true, false).unwrap();
+        filelock.unlock().unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn validate() {
+        let filename = "validate.test";
+        std::fs::write(filename, b"bad!").unwrap();
+
+        let builder =
+            FileLock::new(filename).writeable(true).validate(|file| {
+                let mut magic = [0; 4];
+                file.read_exact_at(&mut magic, 0)?;
+                match &magic {
+                    b"good" => Ok(()),
+                    _ => Err(Error::new(ErrorKind::InvalidData, "bad magic")),
+                }
+            });
+        let err = builder.clone().lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        std::fs::write(filename, b"good").unwrap();
+        let _filelock = builder.lock().unwrap();
+        let pid = Some(process::id() as libc::pid_t);
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 0).unwrap()
+                == vec![seg(0, 0, LockType::Write, pid)]
+        }));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn is_locked() {
+        let filename = "is-locked.test";
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .range(0, 100)
+            .lock()
+            .unwrap();
+        assert!(filelock.is_locked());
+        filelock.downgrade().unwrap();
+        filelock.unlock_range(0, 50).unwrap();
+        assert!(filelock.is_locked());
+        filelock.unlock_range(50, 50).unwrap();
+        assert!(!filelock.is_locked());
+        filelock.lock_range(10, 0, false, false).unwrap();
+        assert!(filelock.is_locked());
+        assert!(filelock.backend_drops_on_any_close());
+        filelock.unlock().unwrap();
+        assert!(!filelock.is_locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn fcntl_passthrough() {
+        use nix::fcntl::FdFlag;
+
+        let filename = "fcntl.test";
+        let filelock = FileLock::lock_exclusive(filename).unwrap();
+        filelock.fcntl(FcntlArg::F_SETFD(FdFlag::empty())).unwrap();
+        assert_eq!(filelock.fcntl(FcntlArg::F_GETFD).unwrap(), 0);
+        filelock
+            .fcntl(FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+            .unwrap();
+        assert_eq!(
+            filelock.fcntl(FcntlArg::F_GETFD).unwrap(),
+            libc::FD_CLOEXEC
+        );
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn wait_free_inotify() {
+        let filename = "inotify.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let err =
+            FileLock::wait_free_inotify(filename, Duration::from_millis(200))
+                .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        let release = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            drop(holder);
+        });
+        let started = std::time::Instant::now();
+        FileLock::wait_free_inotify(filename, Duration::from_secs(10)).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        release.join().unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn default_max_wait() {
+        let filename = "max-wait.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        // the default is process-wide, keep it out of the other tests
+        assert!(in_child(|| {
+            set_default_max_wait(Some(Duration::from_millis(200)));
+            let err = FileLock::lock_exclusive(filename).unwrap_err();
+            let capped = err.kind() == ErrorKind::TimedOut;
+            let started = std::time::Instant::now();
+            let err = FileLock::lock_timeout_thread(
+                filename,
+                true,
+                Duration::from_millis(400),
+            )
+            .unwrap_err();
+            let overridden = err.kind() == ErrorKind::TimedOut
+                && started.elapsed() >= Duration::from_millis(400);
+            set_default_max_wait(None);
+            capped && overridden
+        }));
+        drop(holder);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn disabled() {
+        let filename = "disabled.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let mut filelock = FileLock::new(filename)
+            .writeable(true)
+            .enabled(false)
+            .lock()
+            .unwrap();
+        assert!(filelock.is_locked());
+        assert!(!filelock.backend_drops_on_any_close());
+        filelock.write_all(b"unlocked").unwrap();
+        filelock.downgrade().unwrap();
+        filelock.unlock().unwrap();
+        assert!(!filelock.is_locked());
+        drop(holder);
+
+        let _filelock = FileLock::new(filename)
+            .writeable(true)
+            .enabled(false)
+            .lock()
+            .unwrap();
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 0).unwrap()
+                == vec![seg(0, 0, LockType::Unlocked, None)]
+        }));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_with_fence() {
+        let filename = "fence.test";
+        let _ = remove_file(filename);
+
+        let (filelock, fence) =
+            FileLock::lock_with_fence(filename, true).unwrap();
+        drop(filelock);
+        let (old, same) = FileLock::lock_with_fence(filename, false).unwrap();
+        assert_eq!(fence, same);
+
+        // keep the old inode alive so it can't be reused
+        remove_file(filename).unwrap();
+        let (_filelock, other) =
+            FileLock::lock_with_fence(filename, true).unwrap();
+        assert_ne!(fence, other);
+        drop(old);
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(feature = "leak-check")]
+    #[test]
+    fn leaked_locks() {
+        let filename = "leak.test";
+        let leaked = || {
+            crate::leaked_locks()
+                .into_iter()
+                .filter(|record| record.path.ends_with(filename))
+                .collect::<Vec<_>>()
+        };
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .range(0, 10)
+            .lock()
+            .unwrap();
+        filelock.lock_range(20, 0, false, false).unwrap();
+        let records = leaked();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].ranges, vec![(0, 10), (20, 0)]);
+        assert!(records[0].backtrace.contains("leaked_locks"));
+
+        filelock.unlock().unwrap();
+        assert!(leaked().is_empty());
+        filelock.lock_range(0, 0, false, true).unwrap();
+        drop(filelock);
+        assert!(leaked().is_empty());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn with_exclusive_policy() {
+        let filename = "policy.test";
+        let _ = remove_file(filename);
+        let fail = |_: &mut FileLock| -> Result<()> {
+            Err(Error::new(ErrorKind::InvalidData, "broken"))
+        };
+        let locked = || {
+            in_child(|| {
+                FileLock::lock_map(filename, 0, 0).unwrap()[0].lock_type
+                    == LockType::Write
+            })
+        };
+
+        let value =
+            FileLock::with_exclusive_policy(filename, OnError::Hold, |_| {
+                Ok(42)
+            })
+            .unwrap();
+        assert_eq!(value, 42);
+        assert!(!locked());
+
+        let err =
+            FileLock::with_exclusive_policy(filename, OnError::Hold, fail)
+                .unwrap_err();
+        assert_eq!(err.error.kind(), ErrorKind::InvalidData);
+        assert!(err.held.as_ref().unwrap().is_locked());
+        assert!(locked());
+        drop(err);
+        assert!(!locked());
+
+        let err =
+            FileLock::with_exclusive_policy(filename, OnError::Release, fail)
+                .unwrap_err();
+        assert!(err.held.is_none());
+        assert!(!locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn extend_and_shrink() {
+        let filename = "extend.test";
+        let _ = remove_file(filename);
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .range(0, 10)
+            .lock()
+            .unwrap();
+        let pid = Some(process::id() as libc::pid_t);
+
+        filelock.extend(10).unwrap();
+        assert!(in_child(|| {
+            FileLock::new(filename).range(15, 1).lock().is_err()
+                && FileLock::lock_map(filename, 0, 0).unwrap()
+                    == vec![
+                        seg(0, 20, LockType::Write, pid),
+                        seg(20, 0, LockType::Unlocked, None),
+                    ]
+        }));
+
+        filelock.shrink(5).unwrap();
+        assert!(in_child(|| {
+            FileLock::new(filename).range(5, 15).lock().is_ok()
+                && FileLock::new(filename).range(4, 1).lock().is_err()
+        }));
+
+        filelock.lock_range(30, 0, false, true).unwrap();
+        let err = filelock.extend(10).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        filelock.shrink(0).unwrap();
+        assert!(!filelock.is_locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_many() {
+        let filenames = ["many-a.test", "many-b.test", "many-c.test"];
+        for filename in &filenames {
+            let _ = remove_file(filename);
+        }
+
+        let locks = FileLock::lock_many(&filenames, true).unwrap();
+        assert_eq!(locks.len(), 3);
+        assert!(in_child(|| {
+            filenames.iter().all(|filename| {
+                FileLock::new(filename).lock().unwrap_err().kind()
+                    == ErrorKind::WouldBlock
+            })
+        }));
+        drop(locks);
+
+        let order = std::cell::RefCell::new(Vec::new());
+        let locks = FileLock::lock_many_by_key(&filenames, false, |path| {
+            order.borrow_mut().push(path.to_owned());
+            std::cmp::Reverse(path.to_owned())
+        })
+        .unwrap();
+        assert_eq!(order.into_inner().len(), 3);
+        assert!(locks.iter().all(FileLock::is_locked));
+        drop(locks);
+
+        for filename in &filenames {
+            let _ = remove_file(filename);
+        }
+    }
+
+    #[test]
+    fn init_if_absent() {
+        let filename = "init.test";
+        let _ = remove_file(filename);
+
+        let (mut filelock, created) =
+            FileLock::init_if_absent(filename, b"default").unwrap();
+        assert!(created);
+        let mut contents = String::new();
+        filelock.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "default");
+        drop(filelock);
+
+        std::fs::write(filename, b"edited").unwrap();
+        let (_filelock, created) =
+            FileLock::init_if_absent(filename, b"default").unwrap();
+        assert!(!created);
+        assert_eq!(std::fs::read(filename).unwrap(), b"edited");
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_shared_rw() {
+        let filename = "shared-rw.test";
+        let _ = remove_file(filename);
+
+        let mut filelock = FileLock::lock_shared_rw(filename).unwrap();
+        let reader = hold(|| FileLock::lock_shared(filename).unwrap());
+        let err = filelock.upgrade(false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        drop(reader);
+
+        let read_only = FileLock::lock_shared("Cargo.toml").unwrap();
+        let err = read_only.upgrade(false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+        assert!(read_only.is_locked());
+        drop(read_only);
+
+        filelock.upgrade(false).unwrap();
+        filelock.write_all(b"upgraded").unwrap();
+        let pid = Some(process::id() as libc::pid_t);
+        assert!(in_child(|| {
+            FileLock::lock_map(filename, 0, 0).unwrap()
+                == vec![seg(0, 0, LockType::Write, pid)]
+        }));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_range_overlaps() {
+        use LockType::{Read, Unlocked, Write};
+
+        let filename = "overlaps.test";
+        let _ = remove_file(filename);
+        let pid = Some(process::id() as libc::pid_t);
+        let check = |filelock: &FileLock, expected: &[(u64, u64, LockType)]| {
+            let held: Vec<_> = filelock
+                .held()
+                .iter()
+                .map(|range| {
+                    let len = range.end.map_or(0, |end| end - range.start);
+                    (range.start, len, range.lock_type)
+                })
+                .collect();
+            assert_eq!(held, expected);
+            assert!(in_child(|| {
+                let mut segments = Vec::new();
+                let mut pos = 0;
+                for &(start, len, lock_type) in expected {
+                    if start > pos {
+                        segments.push(seg(pos, start - pos, Unlocked, None));
+                    }
+                    segments.push(seg(start, len, lock_type, pid));
+                    pos = start + len;
+                }
+                if !matches!(expected.last(), Some(&(_, 0, _))) {
+                    segments.push(seg(pos, 0, Unlocked, None));
+                }
+                FileLock::lock_map(filename, 0, 0).unwrap() == segments
+            }));
+        };
+        let read_0_100 = || {
+            let filelock = FileLock::lock_shared_rw(filename).unwrap();
+            filelock.unlock_range(100, 0).unwrap();
+            filelock
+        };
+
+        let filelock = read_0_100();
+        filelock.lock_range(40, 20, false, true).unwrap();
+        check(&filelock, &[(0, 40, Read), (40, 20, Write), (60, 40, Read)]);
+        drop(filelock);
+
+        let filelock = read_0_100();
+        filelock.lock_range(50, 100, false, true).unwrap();
+        check(&filelock, &[(0, 50, Read), (50, 100, Write)]);
+        drop(filelock);
+
+        let filelock = read_0_100();
+        filelock.lock_range(100, 50, false, false).unwrap();
+        check(&filelock, &[(0, 150, Read)]);
+        drop(filelock);
+
+        let filelock = read_0_100();
+        filelock.lock_range(0, 0, false, true).unwrap();
+        check(&filelock, &[(0, 0, Write)]);
+        drop(filelock);
+
+        // a failed conversion changes nothing
+        let filelock = read_0_100();
+        let reader =
+            hold(|| FileLock::new(filename).range(45, 1).lock().unwrap());
+        assert!(filelock.lock_range(40, 20, false, true).is_err());
+        drop(reader);
+        check(&filelock, &[(0, 100, Read)]);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn as_fd() {
+        let filename = "as-fd.test";
+        let filelock = FileLock::lock_exclusive(filename).unwrap();
+        let fd: BorrowedFd<'_> = filelock.as_fd();
+        assert_eq!(fd.as_raw_fd(), filelock.as_raw_fd());
+        drop(filelock);
+
+        fn raw(fd: impl AsFd) -> RawFd {
+            fd.as_fd().as_raw_fd()
+        }
+        let rwlock = FileRwLock::new(filename);
+        let guard = rwlock.write().unwrap();
+        assert_eq!(raw(&guard), guard.file.as_raw_fd());
+        drop(guard);
+        let guard = rwlock.read().unwrap();
+        assert_eq!(raw(&guard), guard.file.as_raw_fd());
+        drop(guard);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_bundle() {
+        let filename = "bundle.test";
+        let _ = remove_file(filename);
+        let locked = || {
+            in_child(|| {
+                FileLock::lock_map(filename, 0, 0).unwrap()[0].lock_type
+                    == LockType::Write
+            })
+        };
+
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let first = order.clone();
+        let second = order.clone();
+        let bundle =
+            LockBundle::new(FileLock::lock_exclusive(filename).unwrap())
+                .before_unlock(move |filelock| {
+                    first.lock().unwrap().push(filelock.is_locked());
+                    Err(Error::new(ErrorKind::Other, "flush failed"))
+                })
+                .before_unlock(move |filelock| {
+                    second.lock().unwrap().push(filelock.is_locked());
+                    Ok(())
+                });
+        assert!(locked());
+        let err = bundle.release().unwrap_err();
+        assert_eq!(err.to_string(), "flush failed");
+        assert_eq!(*order.lock().unwrap(), vec![true, true]);
+        assert!(!locked());
+
+        let flushed = Arc::new(AtomicBool::new(false));
+        let flag = flushed.clone();
+        let bundle =
+            LockBundle::new(FileLock::lock_exclusive(filename).unwrap())
+                .before_unlock(move |_| {
+                    flag.store(true, Ordering::SeqCst);
+                    Ok(())
+                });
+        drop(bundle);
+        assert!(flushed.load(Ordering::SeqCst));
+        assert!(!locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn filesystem_supports_locks() {
+        let dir = std::env::temp_dir();
+        assert!(crate::filesystem_supports_locks(&dir).unwrap());
+        assert!(crate::filesystem_supports_locks(Path::new(".")).unwrap());
+        assert!(crate::filesystem_supports_locks(Path::new("missing.test"))
+            .is_err());
+    }
+
+    #[test]
+    fn downgrade_and_read() {
+        let filename = "downgrade-read.test";
+        let _ = remove_file(filename);
+        let mut filelock = FileLock::new(filename)
+            .writeable(true)
+            .range(4, 8)
+            .lock()
+            .unwrap();
+        filelock.write_all(b"0123456789abcdef").unwrap();
+
+        let mut buf = [0; 16];
+        let n = filelock.downgrade_and_read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"456789ab");
+        assert!(in_child(|| FileLock::new(filename)
+            .range(4, 8)
+            .lock()
+            .is_ok()));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn retry_io() {
+        let filename = "retry.test";
+        let _ = remove_file(filename);
+        let mut filelock = FileLock::lock_exclusive(filename).unwrap();
+        filelock.write_all_retry(b"retried").unwrap();
+        filelock.rewind().unwrap();
+        let mut buf = [0; 16];
+        let n = filelock.read_retry(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"retried");
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn into_buf_writer() {
+        let filename = "buf-writer.test";
+        let _ = remove_file(filename);
+
+        let mut writer = FileLock::lock_exclusive(filename)
+            .unwrap()
+            .into_buf_writer();
+        writer.write_all(b"buffered").unwrap();
+        assert!(writer.get_ref().is_locked());
+        assert_eq!(std::fs::read(filename).unwrap(), b"");
+        writer.close().unwrap();
+        assert_eq!(std::fs::read(filename).unwrap(), b"buffered");
+        assert!(in_child(|| FileLock::new(filename).lock().is_ok()));
+
+        let mut writer = FileLock::lock_exclusive(filename)
+            .unwrap()
+            .into_buf_writer();
+        writer.write_all(b" and dropped").unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read(filename).unwrap(), b" and dropped");
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn create_exclusive_new() {
+        let filename = "create-new.test";
+        let _ = remove_file(filename);
+
+        let (tx, rx) = UnixStream::pair().unwrap();
+        let child = match unsafe { fork() } {
+            Ok(Parent { child }) => child,
+            Ok(Child) => {
+                let mut rx = rx;
+                let _ = rx.read_exact(&mut [0]);
+                let created =
+                    FileLock::create_exclusive_new(filename, b"child").is_ok();
+                process::exit(if created { 0 } else { 1 })
+            }
+            Err(_) => panic!("Error forking tests :("),
+        };
+        (&tx).write_all(&[0]).unwrap();
+        let created = FileLock::create_exclusive_new(filename, b"parent");
+        let child_created =
+            waitpid(child, None) == Ok(WaitStatus::Exited(child, 0));
+        match created {
+            Ok(filelock) => {
+                assert!(!child_created);
+                drop(filelock);
+                assert_eq!(std::fs::read(filename).unwrap(), b"parent");
+            }
+            Err(e) => {
+                assert_eq!(e.kind(), ErrorKind::AlreadyExists);
+                assert!(child_created);
+                assert_eq!(std::fs::read(filename).unwrap(), b"child");
+            }
+        }
+
+        let err =
+            FileLock::create_exclusive_new(filename, b"again").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(feature = "events")]
+    #[test]
+    fn event_sink() {
+        use std::sync::mpsc;
+        use LockEventKind::*;
+
+        let filename = "events.test";
+        let _ = remove_file(filename);
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let release = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            drop(holder);
+        });
+
+        // the sink is process-wide, keep other tests' events out
+        assert!(in_child(|| {
+            let (tx, rx) = mpsc::channel();
+            set_event_sink(tx);
+            let filelock = FileLock::new(filename)
+                .blocking(true)
+                .range(0, 10)
+                .lock()
+                .unwrap();
+            drop(filelock);
+            clear_event_sink();
+            let events: Vec<_> = rx
+                .try_iter()
+                .map(|e| (e.kind, e.start, e.len, e.lock_type))
+                .collect();
+            events
+                == vec![
+                    (Attempt, 0, 10, LockType::Read),
+                    (Blocked, 0, 10, LockType::Read),
+                    (Acquired, 0, 10, LockType::Read),
+                    (Released, 0, 0, LockType::Unlocked),
+                ]
+        }));
+        release.join().unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_timeout_detailed() {
+        let filename = "timeout-detailed.test";
+        let _ = remove_file(filename);
+        let timeout = Duration::from_millis(200);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let info = FileLock::lock_timeout_detailed(filename, true, timeout)
+            .unwrap_err();
+        assert!(info.contended);
+        assert!(info.waited >= timeout);
+        assert_eq!(info.remaining, Duration::ZERO);
+        drop(holder);
+
+        let info = FileLock::lock_timeout_detailed(
+            "missing/file.test",
+            false,
+            timeout,
+        )
+        .unwrap_err();
+        assert!(!info.contended);
+        assert_eq!(info.error.kind(), ErrorKind::NotFound);
+        assert!(info.remaining > Duration::ZERO);
+
+        FileLock::lock_timeout_detailed(filename, true, timeout).unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_registered() {
+        let filename = "registered.test";
+        let registry = Path::new("registry.test");
+        let _ = remove_file(filename);
+        let _ = remove_file(registry);
+
+        let first =
+            FileLock::lock_registered(filename, registry, true).unwrap();
+        let path = std::fs::canonicalize(filename).unwrap();
+        let leases = std::fs::read_to_string(registry).unwrap();
+        let fields: Vec<_> = leases.trim_end().splitn(5, ' ').collect();
+        assert_eq!(fields[0], process::id().to_string());
+        assert_eq!(fields[1..3], ["0", "0"]);
+        assert_eq!(fields[4], path.to_str().unwrap());
+        assert!(in_child(|| FileLock::new(filename).lock().is_err()));
+
+        first.release().unwrap();
+        assert_eq!(std::fs::read_to_string(registry).unwrap(), "");
+        assert!(in_child(|| FileLock::new(filename).lock().is_ok()));
+
+        let second =
+            FileLock::lock_registered(filename, registry, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(registry).unwrap().lines().count(),
+            1
+        );
+        drop(second);
+        assert_eq!(std::fs::read_to_string(registry).unwrap(), "");
+        let _ = remove_file(filename);
+        let _ = remove_file(registry);
+    }
+
+    #[test]
+    fn build_flock() {
+        use crate::build_flock;
+        use libc::{off_t, SEEK_CUR, SEEK_END, SEEK_SET};
+
+        let bad = |whence, start, len| {
+            build_flock(LockType::Write, whence, start, len)
+                .unwrap_err()
+                .kind()
+                == ErrorKind::InvalidInput
+        };
+        assert!(bad(42, 0, 0));
+        assert!(bad(SEEK_SET, -1, 0));
+        assert!(bad(SEEK_SET, 10, -11));
+        assert!(bad(SEEK_SET, 10, off_t::MAX));
+        assert!(bad(SEEK_CUR, off_t::MIN, -1));
+        // only the kernel knows where these bases are
+        assert!(build_flock(LockType::Write, SEEK_CUR, -5, 0).is_ok());
+        let flock = build_flock(LockType::Read, SEEK_END, -10, 10).unwrap();
+        assert_eq!(flock.l_whence, SEEK_END as libc::c_short);
+        assert_eq!(flock.l_type, libc::F_RDLCK as libc::c_short);
+        assert_eq!((flock.l_start, flock.l_len), (-10, 10));
+
+        // random inputs, biased towards the edges: never panics, and
+        // whatever is accepted is a range within 0..=off_t::MAX for SEEK_SET
+        let edges = [off_t::MIN, -2, -1, 0, 1, 2, off_t::MAX - 1, off_t::MAX];
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..100_000 {
+            let mut value = || match next() % 3 {
+                0 => edges[(next() % edges.len() as u64) as usize],
+                1 => (next() % 4096) as off_t - 2048,
+                _ => next() as off_t,
+            };
+            let (start, len) = (value(), value());
+            let whence =
+                [SEEK_SET, SEEK_CUR, SEEK_END, -1][(next() % 4) as usize];
+            match build_flock(LockType::Write, whence, start, len) {
+                Ok(flock) => {
+                    assert_eq!((flock.l_start, flock.l_len), (start, len));
+                    assert!(whence >= 0);
+                    if whence == SEEK_SET {
+                        let (first, last) = match len {
+                            0 => (start as i128, start as i128),
+                            len if len > 0 => {
+                                (start as i128, start as i128 + len as i128 - 1)
+                            }
+                            len => {
+                                (start as i128 + len as i128, start as i128 - 1)
+                            }
+                        };
+                        assert!(first >= 0 && last <= off_t::MAX as i128);
+                    }
+                }
+                Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidInput),
+            }
+        }
+    }
+
+    #[test]
+    fn from_file() {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let filename = "from-file.test";
+        let _ = remove_file(filename);
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(filename)
+            .unwrap();
+        let mut filelock = FileLock::from_file(file, false, true).unwrap();
+        filelock.write_all(b"one").unwrap();
+        filelock.seek(SeekFrom::Start(0)).unwrap();
+        filelock.write_all(b"two").unwrap();
+        let mode = filelock.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(in_child(|| FileLock::new(filename).lock().is_err()));
+        drop(filelock);
+        assert_eq!(std::fs::read(filename).unwrap(), b"onetwo");
+
+        let read_only = File::open(filename).unwrap();
+        let err = FileLock::from_file(read_only, false, true).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_fd() {
+        let filename = "lock-fd.test";
+        let _ = remove_file(filename);
+        let file = File::create(filename).unwrap();
+
+        let fdlock = FileLock::lock_fd(&file, false, true).unwrap();
+        assert_eq!(fdlock.as_raw_fd(), file.as_raw_fd());
+        fdlock.file().write_all(b"through the guard").unwrap();
+        assert!(in_child(|| FileLock::new(filename).lock().is_err()));
+        fdlock.unlock_range(0, 7).unwrap();
+        assert!(in_child(|| FileLock::new(filename)
+            .range(0, 7)
+            .lock()
+            .is_ok()));
+        drop(fdlock);
+        assert!(in_child(|| FileLock::new(filename).lock().is_ok()));
+
+        // the descriptor is still open
+        (&file).write_all(b", and after").unwrap();
+        let read_only = File::open(filename).unwrap();
+        assert!(FileLock::lock_fd(&read_only, false, true).is_err());
+        (&read_only).read_exact(&mut [0; 4]).unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn ofd() {
+        let filename = "ofd.test";
+        let _ = remove_file(filename);
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .ofd(true)
+            .lock()
+            .unwrap();
+        assert!(!filelock.backend_drops_on_any_close());
+        // closing another descriptor keeps the lock
+        drop(File::open(filename).unwrap());
+        assert!(in_child(|| FileLock::new(filename).lock().is_err()));
+
+        // conflicts with other handles of the same process
+        let err = FileLock::new(filename).ofd(true).lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let waiter = std::thread::spawn(move || {
+            FileLock::new(filename)
+                .blocking(true)
+                .ofd(true)
+                .lock()
+                .unwrap()
+        });
+        sleep(Duration::from_millis(100));
+        filelock.downgrade().unwrap();
+        assert!(waiter.join().unwrap().is_locked());
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn flock_backend() {
+        let filename = "flock.test";
+        let _ = remove_file(filename);
+
+        let builder = FileLock::new(filename).writeable(true).flock(true);
+        let filelock = builder.clone().lock().unwrap();
+        assert!(!filelock.backend_drops_on_any_close());
+        let err = builder.clone().lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let err = filelock.unlock_range(10, 0).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(builder.clone().range(0, 10).lock().is_err());
+        // fcntl() locks are independent on Linux
+        #[cfg(target_os = "linux")]
+        assert!(in_child(|| FileLock::new(filename).lock().is_ok()));
+
+        let waiter = std::thread::spawn(move || {
+            builder.blocking(true).writeable(false).lock().unwrap()
+        });
+        sleep(Duration::from_millis(100));
+        filelock.downgrade().unwrap();
+        assert!(waiter.join().unwrap().is_locked());
+        filelock.unlock().unwrap();
+        assert!(!filelock.is_locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn builder_lock_timeout() {
+        let filename = "builder-timeout.test";
+        let _ = remove_file(filename);
+        let timeout = Duration::from_millis(200);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        for builder in [
+            FileLock::new(filename).writeable(true),
+            FileLock::new(filename).range(10, 10),
+        ] {
+            let started = std::time::Instant::now();
+            let err = builder.lock_timeout(timeout).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert!(started.elapsed() >= timeout);
+        }
+
+        let release = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(holder);
+        });
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .lock_timeout(Duration::from_secs(10))
+            .unwrap();
+        assert!(filelock.is_locked());
+        release.join().unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn try_lock_until() {
+        let filename = "lock-until.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let deadline = std::time::Instant::now() + Duration::from_millis(200);
+        let err = FileLock::new(filename)
+            .try_lock_until(deadline)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(std::time::Instant::now() >= deadline);
+        assert!(err.to_string().contains("after"));
+
+        let err = FileLock::new(filename)
+            .try_lock_until(deadline)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        drop(holder);
+        FileLock::new(filename).try_lock_until(deadline).unwrap();
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn retries() {
+        let filename = "retries.test";
+        let _ = remove_file(filename);
+        let builder = FileLock::new(filename)
+            .writeable(true)
+            .retries(3)
+            .backoff(Backoff::Fixed(Duration::from_millis(100)));
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let started = std::time::Instant::now();
+        let err = builder.clone().lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(started.elapsed() >= Duration::from_millis(150));
+
+        let release = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(holder);
+        });
+        let filelock = builder.retries(50).lock().unwrap();
+        assert!(filelock.is_locked());
+        release.join().unwrap();
+
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(50),
+        };
+        for (attempt, full) in [(0, 10), (1, 20), (2, 40), (3, 50), (40, 50)] {
+            let delay = backoff.delay(attempt);
+            let full = Duration::from_millis(full);
+            assert!(delay >= full / 2 && delay <= full);
+        }
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn try_lock() {
+        let filename = "try-lock.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let err = FileLock::new(filename)
+            .blocking(true)
+            .try_lock()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let err = FileLock::new("missing/try-lock.test")
+            .try_lock()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        drop(holder);
+        assert!(FileLock::new(filename).try_lock().unwrap().is_locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn lock_detailed() {
+        let filename = "lock-detailed.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let err = FileLock::new(filename).lock_detailed().unwrap_err();
+        assert!(matches!(err, FileLockError::Contended(_)));
+        assert_eq!(Error::from(err).kind(), ErrorKind::WouldBlock);
+        let err = FileLock::new("missing/lock-detailed.test")
+            .lock_detailed()
+            .unwrap_err();
+        assert!(matches!(err, FileLockError::Open(_)));
+        assert_eq!(err.io_error().kind(), ErrorKind::NotFound);
+        drop(holder);
+        assert!(FileLock::new(filename).lock_detailed().unwrap().is_locked());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn blocking_holder() {
+        let filename = "blocking-holder.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, b"0123456789").unwrap();
+
+        assert_eq!(FileLock::blocking_holder(filename, true).unwrap(), None);
+        let holder = hold(|| {
+            let filelock = FileLock::lock_shared(filename).unwrap();
+            filelock.unlock_range(0, 4).unwrap();
+            filelock
+        });
+        assert_eq!(FileLock::blocking_holder(filename, false).unwrap(), None);
+        assert_eq!(
+            FileLock::blocking_holder(filename, true).unwrap(),
+            Some(LockInfo {
+                start: 4,
+                len: 0,
+                lock_type: LockType::Read,
+                pid: holder.child.as_raw(),
+            })
+        );
+        drop(holder);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn is_locked_at() {
+        let filename = "is-locked-at.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, b"").unwrap();
+
+        assert!(!FileLock::is_locked_at(filename).unwrap());
+        assert!(FileLock::can_lock(filename, true).unwrap());
+        let holder = hold(|| FileLock::lock_shared(filename).unwrap());
+        assert!(FileLock::is_locked_at(filename).unwrap());
+        assert!(FileLock::can_lock(filename, false).unwrap());
+        assert!(!FileLock::can_lock(filename, true).unwrap());
+        drop(holder);
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        assert!(!FileLock::can_lock(filename, false).unwrap());
+        drop(holder);
+        assert!(!FileLock::is_locked_at(filename).unwrap());
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_lock() {
+        use std::{
+            future::Future,
+            pin::Pin,
+            sync::Arc,
+            task::{Context, Poll, Wake, Waker},
+            thread,
+        };
+
+        struct Unpark(thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        let filename = "async-lock.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = AsyncFileLock::lock(filename, true);
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        drop(holder);
+        let filelock = loop {
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(result) => break result.unwrap(),
+                Poll::Pending => thread::park(),
+            }
+        };
+        assert!(filelock.is_locked());
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(any(feature = "futures-io", feature = "tokio"))]
+    #[test]
+    fn async_io() {
+        use std::{
+            future::Future,
+            pin::Pin,
+            sync::Arc,
+            task::{Context, Poll, Wake, Waker},
+        };
+
+        struct Noop;
+        impl Wake for Noop {
+            fn wake(self: Arc<Self>) {}
+        }
+
+        let filename = "async-io.test";
+        let _ = remove_file(filename);
+
+        let waker = Waker::from(Arc::new(Noop));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = AsyncFileLock::lock(filename, true);
+        let mut filelock = loop {
+            if let Poll::Ready(result) = Pin::new(&mut future).poll(&mut cx) {
+                break result.unwrap();
+            }
+            std::thread::yield_now();
+        };
+
+        #[cfg(feature = "futures-io")]
+        {
+            use futures_io::{AsyncRead, AsyncSeek, AsyncWrite};
+
+            let mut pinned = Pin::new(&mut filelock);
+            let written = pinned.as_mut().poll_write(&mut cx, b"futures");
+            assert!(matches!(written, Poll::Ready(Ok(7))));
+            let sought = pinned.as_mut().poll_seek(&mut cx, SeekFrom::Start(0));
+            assert!(matches!(sought, Poll::Ready(Ok(0))));
+            let mut buf = [0; 16];
+            let read = pinned.as_mut().poll_read(&mut cx, &mut buf);
+            assert!(matches!(read, Poll::Ready(Ok(7))));
+            assert_eq!(&buf[..7], b"futures");
+            filelock.set_len(0).unwrap();
+            filelock.rewind().unwrap();
+        }
+
+        #[cfg(feature = "tokio")]
+        {
+            use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+
+            let mut pinned = Pin::new(&mut filelock);
+            let written = pinned.as_mut().poll_write(&mut cx, b"tokio");
+            assert!(matches!(written, Poll::Ready(Ok(5))));
+            pinned.as_mut().start_seek(SeekFrom::Start(1)).unwrap();
+            let sought = pinned.as_mut().poll_complete(&mut cx);
+            assert!(matches!(sought, Poll::Ready(Ok(1))));
+            let mut buf = [0; 16];
+            let mut buf = ReadBuf::new(&mut buf);
+            let read = pinned.as_mut().poll_read(&mut cx, &mut buf);
+            assert!(matches!(read, Poll::Ready(Ok(()))));
+            assert_eq!(buf.filled(), b"okio");
+        }
+
+        assert!(filelock.is_locked());
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize() {
+        fn is_serialize<T: serde::Serialize>() {}
+
+        is_serialize::<LockType>();
+        is_serialize::<LockInfo>();
+        is_serialize::<LockSegment>();
+        is_serialize::<HolderInfo>();
+    }
+
+    #[test]
+    fn cancel_token() {
+        let filename = "cancel-token.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+        let token = CancelToken::new();
+        let waiter = {
+            let token = token.clone();
+            std::thread::spawn(move || {
+                FileLock::new(filename)
+                    .blocking(true)
+                    .cancel_token(token)
+                    .lock()
+            })
+        };
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(!waiter.is_finished());
+        token.cancel();
+        let err = waiter.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        assert!(token.is_cancelled());
+
+        // a cancelled token stops waits before they start
+        let err = FileLock::new(filename)
+            .blocking(true)
+            .cancel_token(token)
+            .lock_timeout(Duration::from_secs(10))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Interrupted);
+        drop(holder);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn on_interrupt() {
+        use nix::sys::signal::{
+            sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal,
+        };
+
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        /// Lock `filename`, held by another process until `signals` have
+        /// been sent to the waiting thread
+        fn lock_interrupted(
+            filename: &'static str,
+            policy: OnInterrupt,
+            signals: usize,
+        ) -> Result<FileLock> {
+            let holder = hold(|| FileLock::lock_exclusive(filename).unwrap());
+            let waiter = unsafe { libc::pthread_self() };
+            let signaller = std::thread::spawn(move || {
+                for _ in 0..signals {
+                    std::thread::sleep(Duration::from_millis(100));
+                    unsafe { libc::pthread_kill(waiter, libc::SIGUSR1) };
+                }
+                std::thread::sleep(Duration::from_millis(100));
+                drop(holder);
+            });
+            let result = FileLock::new(filename)
+                .blocking(true)
+                .on_interrupt(policy)
+                .lock();
+            signaller.join().unwrap();
+            result
+        }
+
+        let filename = "on-interrupt.test";
+        let _ = remove_file(filename);
+
+        // the handler is process-wide, keep it out of the other tests
+        assert!(in_child(|| {
+            let action = SigAction::new(
+                SigHandler::Handler(ignore),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            unsafe { sigaction(Signal::SIGUSR1, &action).unwrap() };
+            let interrupted = |result: Result<FileLock>| matches!(result, Err(e) if e.kind() == ErrorKind::Interrupted);
+            interrupted(lock_interrupted(filename, OnInterrupt::Return, 1))
+                && interrupted(lock_interrupted(
+                    filename,
+                    OnInterrupt::RetryUpTo(1),
+                    2,
+                ))
+                && lock_interrupted(filename, OnInterrupt::RetryUpTo(2), 2)
+                    .is_ok()
+                && lock_interrupted(filename, OnInterrupt::Retry, 3).is_ok()
+        }));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn with_lock() {
+        let filename = "with-lock.test";
+        let _ = remove_file(filename);
+
+        let len = FileLock::new(filename)
+            .writeable(true)
+            .with_lock(|filelock| {
+                filelock.write_all(b"0123456789")?;
+                assert!(!in_child(
+                    || FileLock::can_lock(filename, false).unwrap()
+                ));
+                Ok(filelock.metadata()?.len())
+            })
+            .unwrap();
+        assert_eq!(len, 10);
+        assert!(in_child(|| FileLock::can_lock(filename, true).unwrap()));
+
+        let err = FileLock::new(filename)
+            .with_lock(|filelock| filelock.write_all(b"read-only"))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBADF));
+        assert!(in_child(|| FileLock::can_lock(filename, true).unwrap()));
+
+        let panicked = std::panic::catch_unwind(|| {
+            FileLock::new(filename)
+                .writeable(true)
+                .with_lock(|_| -> Result<()> { panic!("oops") })
+        });
+        assert!(panicked.is_err());
+        assert!(in_child(|| FileLock::can_lock(filename, true).unwrap()));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn file_rw_lock() {
+        let filename = "file-rw-lock.test";
+        let _ = remove_file(filename);
+
+        let lock = FileRwLock::new(filename);
+        let err = lock.try_read().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let mut writer = lock.try_write().unwrap();
+        writer.write_all(b"0123456789").unwrap();
+        assert!(in_child(|| FileRwLock::new(filename)
+            .try_read()
+            .err()
+            .map(|e| e.kind())
+            == Some(ErrorKind::WouldBlock)));
+        drop(writer);
+
+        let mut reader = lock.read().unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "0123456789");
+        assert!(in_child(|| {
+            let lock = FileRwLock::new(filename);
+            lock.try_read().is_ok()
+                && lock.try_write().err().map(|e| e.kind())
+                    == Some(ErrorKind::WouldBlock)
+        }));
+        #[cfg(target_os = "linux")]
+        {
+            let err = lock.try_write().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::WouldBlock);
+            let second = lock.try_read().unwrap();
+            drop(second);
+            assert!(reader.is_locked());
+            assert!(in_child(|| FileRwLock::new(filename)
+                .try_write()
+                .err()
+                .map(|e| e.kind())
+                == Some(ErrorKind::WouldBlock)));
+        }
+        drop(reader);
+        assert!(lock.try_write().is_ok());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn persist_lock() {
+        let filename = "persist-lock.test";
+        let _ = remove_file(filename);
+
+        let file = FileLock::lock_exclusive(filename).unwrap().persist_lock();
+        assert!(in_child(|| !FileLock::can_lock(filename, false).unwrap()));
+        drop(file);
+        assert!(in_child(|| FileLock::can_lock(filename, true).unwrap()));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn on_drop() {
+        let filename = "on-drop.test";
+        let _ = remove_file(filename);
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .on_drop(OnDrop::Delete)
+            .lock()
+            .unwrap();
+        assert!(Path::new(filename).exists());
+        drop(filelock);
+        assert!(!Path::new(filename).exists());
+
+        // not removed without an exclusive lock
+        std::fs::write(filename, b"").unwrap();
+        drop(
+            FileLock::new(filename)
+                .on_drop(OnDrop::Delete)
+                .lock()
+                .unwrap(),
+        );
+        assert!(Path::new(filename).exists());
+
+        // nor when the path was reused meanwhile
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .on_drop(OnDrop::Delete)
+            .lock()
+            .unwrap();
+        remove_file(filename).unwrap();
+        std::fs::write(filename, b"new").unwrap();
+        drop(filelock);
+        assert_eq!(std::fs::read(filename).unwrap(), b"new");
+
+        // a duplicated descriptor keeps an OFD lock alive after close
+        #[cfg(target_os = "linux")]
+        {
+            let filelock = FileLock::new(filename)
+                .writeable(true)
+                .ofd(true)
+                .on_drop(OnDrop::Close)
+                .lock()
+                .unwrap();
+            let dup = filelock.try_clone().unwrap();
+            drop(filelock);
+            assert!(in_child(|| !FileLock::can_lock(filename, true).unwrap()));
+            drop(dup);
+            assert!(in_child(|| FileLock::can_lock(filename, true).unwrap()));
+        }
+        let _ = remove_file(filename);
     }
-}
 
-/// Builder to create [`FileLock`](struct.FileLock.html)
-///
-/// blocking and writeable default to false
-#[derive(Debug)]
-pub struct FileLockBuilder<T> {
-    file_path: T,
-    blocking: bool,
-    writeable: bool,
-}
+    #[test]
+    fn on_drop_delete_race() {
+        let filename = "on-drop-delete-race.test";
+        let _ = remove_file(filename);
 
-impl<T: AsRef<Path>> FileLockBuilder<T> {
-    /// Set lock to blocking mode
-    pub fn blocking(mut self, v: bool) -> Self {
-        self.blocking = v;
-        self
+        let holder = hold(|| {
+            FileLock::new(filename)
+                .writeable(true)
+                .on_drop(OnDrop::Delete)
+                .lock()
+                .unwrap()
+        });
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(holder);
+        });
+        // opens the file before the holder removes it, and only gets the
+        // lock afterwards
+        let filelock = FileLock::new(filename)
+            .blocking(true)
+            .writeable(true)
+            .on_drop(OnDrop::Delete)
+            .lock()
+            .unwrap();
+        releaser.join().unwrap();
+        assert!(same_file(&filelock, Path::new(filename)).unwrap());
+        assert!(in_child(|| !FileLock::can_lock(filename, true).unwrap()));
+        drop(filelock);
+        assert!(!Path::new(filename).exists());
     }
 
-    /// Open file as writeable and get exclusive lock
-    pub fn writeable(mut self, v: bool) -> Self {
-        self.writeable = v;
-        self
+    #[test]
+    fn pid_file() {
+        let filename = "pid-file.test";
+        let _ = remove_file(filename);
+
+        assert_eq!(PidFile::read_pid(filename).unwrap(), None);
+        // stale pidfile from a dead instance
+        std::fs::write(filename, b"1\n").unwrap();
+        assert_eq!(PidFile::read_pid(filename).unwrap(), None);
+
+        let holder = hold(|| PidFile::create(filename).unwrap());
+        let err = PidFile::create(filename).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(err.to_string().contains(&holder.child.to_string()));
+        assert_eq!(
+            PidFile::read_pid(filename).unwrap(),
+            Some(holder.child.as_raw())
+        );
+        drop(holder);
+        assert!(!Path::new(filename).exists());
+
+        let pidfile = PidFile::create(filename).unwrap();
+        let mut buf = [0; 32];
+        let n = pidfile.file_lock().read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf[..n], format!("{}\n", std::process::id()).as_bytes());
+        drop(pidfile);
+        assert!(!Path::new(filename).exists());
     }
 
-    /// Create a [`FileLock`](struct.FileLock.html) with these parameters.
-    /// Calls [`FileLock::lock`](struct.FileLock.html#method.lock)
-    pub fn lock(self) -> Result<FileLock> {
-        FileLock::lock(self.file_path, self.blocking, self.writeable)
+    #[test]
+    fn pid_file_is_stale() {
+        let filename = "pid-file-is-stale.test";
+        let _ = remove_file(filename);
+
+        assert!(!PidFile::is_stale(filename).unwrap());
+        let dead = match unsafe { fork() }.unwrap() {
+            Parent { child } => {
+                waitpid(child, None).unwrap();
+                child
+            }
+            Child => process::exit(0),
+        };
+        std::fs::write(filename, format!("{}\n", dead)).unwrap();
+        assert!(PidFile::is_stale(filename).unwrap());
+        std::fs::write(filename, format!("{}\n", process::id())).unwrap();
+        assert!(!PidFile::is_stale(filename).unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            // written before this process started: the PID was reused
+            use nix::sys::{
+                stat::futimens,
+                time::{TimeSpec, TimeValLike},
+            };
+
+            let file = OpenOptions::new().write(true).open(filename).unwrap();
+            let epoch = TimeSpec::zero();
+            futimens(file.as_raw_fd(), &epoch, &epoch).unwrap();
+            drop(file);
+            assert!(PidFile::is_stale(filename).unwrap());
+        }
+
+        let holder = hold(|| PidFile::create(filename).unwrap());
+        let err = PidFile::create_breaking_stale(filename).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let mut file = OpenOptions::new().write(true).open(filename).unwrap();
+        file.set_len(0).unwrap();
+        write!(file, "{}", dead).unwrap();
+        drop(file);
+        let pidfile = PidFile::create_breaking_stale(filename).unwrap();
+        drop(holder);
+        assert!(Path::new(filename).exists());
+        drop(pidfile);
+        assert!(!Path::new(filename).exists());
     }
-}
 
-impl Drop for FileLock {
-    fn drop(&mut self) {
-        let _ = self.unlock();
+    #[test]
+    fn pid_file_breaking_race() {
+        let filename = "pid-file-breaking-race.test";
+        let _ = remove_file(filename);
+
+        let dead = match unsafe { fork() }.unwrap() {
+            Parent { child } => {
+                waitpid(child, None).unwrap();
+                child
+            }
+            Child => process::exit(0),
+        };
+        let holder = hold(|| {
+            let mut filelock = FileLock::lock_exclusive(filename).unwrap();
+            write!(filelock, "{}", dead).unwrap();
+            filelock
+        });
+
+        let mut children = Vec::new();
+        for _ in 0..4 {
+            match unsafe { fork() }.unwrap() {
+                Parent { child } => children.push(child),
+                Child => {
+                    let code = match PidFile::create_breaking_stale(filename) {
+                        Ok(pidfile) => {
+                            sleep(Duration::from_millis(200));
+                            let path = Path::new(filename);
+                            match same_file(pidfile.file_lock(), path) {
+                                Ok(true) => 0,
+                                _ => 2,
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => 1,
+                        Err(_) => 3,
+                    };
+                    process::exit(code);
+                }
+            }
+        }
+        let codes: Vec<_> = children
+            .into_iter()
+            .map(|child| match waitpid(child, None) {
+                Ok(WaitStatus::Exited(_, code)) => code,
+                status => panic!("{:?}", status),
+            })
+            .collect();
+        assert!(codes.contains(&0), "{:?}", codes);
+        assert!(codes.iter().all(|&code| code < 2), "{:?}", codes);
+        drop(holder);
+        let _ = remove_file(filename);
     }
-}
 
-fn cver(e: nix::Error) -> Error {
-    match e.as_errno() {
-        Some(e) => Error::from_raw_os_error(e as i32),
-        None => Error::new(ErrorKind::Other, e),
+    #[test]
+    fn record_holder() {
+        let filename = "record-holder.test";
+        let _ = remove_file(filename);
+
+        let err = FileLock::new(filename)
+            .record_holder("reader")
+            .lock()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let before = std::time::SystemTime::now() - Duration::from_secs(1);
+        let holder = hold(|| {
+            FileLock::new(filename)
+                .writeable(true)
+                .record_holder("line\nbreak")
+                .lock()
+                .unwrap()
+        });
+        let info = HolderInfo::read(filename).unwrap().unwrap();
+        assert_eq!(info.pid, holder.child.as_raw() as u32);
+        assert_eq!(info.label, "line break");
+        assert!(!info.hostname.is_empty());
+        #[cfg(target_os = "linux")]
+        assert!(info.boot_id.is_some());
+        assert!(info.time >= before);
+        drop(holder);
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .record_holder("")
+            .lock()
+            .unwrap();
+        let info = filelock.holder_info().unwrap().unwrap();
+        assert_eq!(info.pid, process::id());
+        assert_eq!(HolderInfo::parse(b"garbage"), None);
+        drop(filelock);
+        let _ = remove_file(filename);
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn single_instance() {
+        let dir = Path::new("single-instance.test");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
 
-    use nix::unistd::fork;
-    use nix::unistd::ForkResult::{Child, Parent};
-    use std::fs::remove_file;
-    use std::process;
-    use std::thread::sleep;
-    use std::time::Duration;
+        let err = SingleInstance::acquire("../app").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        // the environment is process-wide, keep it out of the other tests
+        assert!(in_child(|| {
+            std::env::set_var("XDG_RUNTIME_DIR", dir);
+            let holder = hold(|| SingleInstance::acquire("app").unwrap());
+            let other = SingleInstance::acquire("app").unwrap();
+            let detected = other.already_running()
+                && other.other_pid() == Some(holder.child.as_raw())
+                && other.path() == dir.join("app.lock");
+            drop(holder);
+            let first = SingleInstance::acquire("app").unwrap();
+            detected && !first.already_running() && first.other_pid().is_none()
+        }));
+        let _ = std::fs::remove_dir_all(dir);
+    }
 
     #[test]
-    fn lock_and_unlock() {
-        let filename = "filelock.test";
+    fn replace() {
+        let dir = Path::new("replace.test");
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let target = dir.join("target");
+        std::fs::write(&target, b"old").unwrap();
 
-        for already_exists in &[true, false] {
-            for already_locked in &[true, false] {
-                for already_writable in &[true, false] {
-                    for is_blocking in &[true, false] {
-                        for is_writable in &[true, false] {
-                            if !*already_exists
-                                && (*already_locked || *already_writable)
-                            {
-                                // nonsensical tests
-                                continue;
-                            }
+        let mut replacement = FileLock::replace(&target).unwrap();
+        replacement.write_all(b"new contents").unwrap();
+        assert!(in_child(|| std::fs::read(&target).unwrap() == b"old"
+            && !FileLock::can_lock(&target, false).unwrap()));
+        replacement.commit().unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"new contents");
 
-                            let _ = remove_file(&filename);
+        let mut replacement = FileLock::replace(&target).unwrap();
+        replacement.write_all(b"discarded").unwrap();
+        replacement.abort().unwrap();
+        drop(FileLock::replace(&target).unwrap());
+        assert_eq!(std::fs::read(&target).unwrap(), b"new contents");
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 1);
 
-                            let parent_lock = match *already_exists {
-                                false => None,
-                                true => {
-                                    let _ = OpenOptions::new()
-                                        .write(true)
-                                        .create(true)
-                                        .open(&filename);
+        // a writer waiting meanwhile locks the new file
+        struct CommitOnDrop(Option<Replacement>);
+        impl Drop for CommitOnDrop {
+            fn drop(&mut self) {
+                self.0.take().unwrap().commit().unwrap();
+            }
+        }
+        let holder = hold(|| {
+            let mut replacement = FileLock::replace(&target).unwrap();
+            replacement.write_all(b"from child").unwrap();
+            CommitOnDrop(Some(replacement))
+        });
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(holder);
+        });
+        let replacement = FileLock::replace(&target).unwrap();
+        releaser.join().unwrap();
+        assert!(same_file(replacement.file_lock(), &target).unwrap());
+        drop(replacement);
+        assert_eq!(std::fs::read(&target).unwrap(), b"from child");
+        let _ = std::fs::remove_dir_all(dir);
+    }
 
-                                    match *already_locked {
-                                        false => None,
-                                        true => {
-                                            match FileLock::lock(&filename, true, *already_writable)
-                                            {
-                                                Ok(lock) => Some(lock),
-                                                Err(err) => {
-                                                    panic!("Error creating parent lock ({})", err)
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            };
+    #[test]
+    fn update() {
+        let filename = "update.test";
+        let _ = remove_file(filename);
 
-                            match fork() {
-                                Ok(Parent { child: _ }) => {
-                                    sleep(Duration::from_millis(150));
+        FileLock::update(filename, |contents| {
+            assert!(contents.is_empty());
+            b"a longer first version".to_vec()
+        })
+        .unwrap();
+        FileLock::update(filename, |contents| contents[..8].to_vec()).unwrap();
+        assert_eq!(std::fs::read(filename).unwrap(), b"a longer");
+        let _ = remove_file(filename);
+    }
 
-                                    match parent_lock {
-                                        Some(lock) => {
-                                            let _ = lock.unlock();
-                                        }
-                                        None => {}
-                                    }
+    #[test]
+    fn link_lockfile() {
+        let filename = "link-lockfile.test";
+        let lockfile = "link-lockfile.test.lock";
+        let _ = remove_file(filename);
+        let _ = remove_file(lockfile);
+        std::fs::write(filename, b"").unwrap();
 
-                                    sleep(Duration::from_millis(350));
-                                }
-                                Ok(Child) => {
-                                    let mut try_count = 0;
-                                    let mut locked = false;
+        let builder = || FileLock::new(filename).link_lockfile(true);
+        let first = builder().lock().unwrap();
+        let contents = std::fs::read_to_string(lockfile).unwrap();
+        assert!(contents.contains(&format!(":{}:", process::id())));
+        // handles exclude each other, even shared locks in one process
+        let err = builder().lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let err = builder()
+            .lock_timeout(Duration::from_millis(100))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        let err = first.lock_range(0, 10, false, false).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(!first.backend_drops_on_any_close());
+        drop(first);
+        assert!(!Path::new(lockfile).exists());
 
-                                    match *already_locked {
-                                        true => match *is_blocking {
-                                            true => {
-                                                match FileLock::lock(filename, *is_blocking, *is_writable) {
-                                                    Ok(_)  => { locked = true },
-                                                    Err(_) => panic!("Error getting lock after wating for release"),
-                                                }
-                                            }
-                                            false => {
-                                                for _ in 0..5 {
-                                                    match FileLock::lock(
-                                                        filename,
-                                                        *is_blocking,
-                                                        *is_writable,
-                                                    ) {
-                                                        Ok(_) => {
-                                                            locked = true;
-                                                            break;
-                                                        }
-                                                        Err(_) => {
-                                                            sleep(Duration::from_millis(50));
-                                                            try_count = try_count + 1;
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        },
-                                        false => match FileLock::lock(
-                                            filename,
-                                            *is_blocking,
-                                            *is_writable,
-                                        ) {
-                                            Ok(_) => locked = true,
-                                            Err(_) => match !*already_exists && !*is_writable {
-                                                true => {}
-                                                false => {
-                                                    panic!("Error getting lock with no competition")
-                                                }
-                                            },
-                                        },
-                                    }
+        let second = builder().writeable(true).retries(1).lock().unwrap();
+        second.downgrade().unwrap();
+        assert!(Path::new(lockfile).exists());
+        drop(second);
+        assert!(!Path::new(lockfile).exists());
 
-                                    match !*already_exists && !is_writable {
-                                        true => assert!(
-                                            locked == false,
-                                            "Locking a non-existent file for reading should fail"
-                                        ),
-                                        false => assert!(
-                                            locked == true,
-                                            "Lock should have been successful"
-                                        ),
-                                    }
+        let third = builder().lock().unwrap();
+        let file = third.into_inner().unwrap();
+        assert!(!Path::new(lockfile).exists());
+        drop(file);
+        assert_eq!(
+            std::fs::read_dir(".")
+                .unwrap()
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .ends_with(".tmp")
+                })
+                .count(),
+            0
+        );
+        let _ = remove_file(filename);
+    }
 
-                                    match *is_blocking {
-                                        true  => assert!(try_count == 0, "Try count should be zero when blocking"),
-                                        false => {
-                                            match *already_locked {
-                                                false => assert!(try_count == 0, "Try count should be zero when no competition"),
-                                                true  => match !*already_writable && !is_writable {
-                                                    true  => assert!(try_count == 0, "Read lock when locked for reading should succeed first go"),
-                                                    false => assert!(try_count >= 3, "Try count should be >= 3"),
-                                                },
-                                            }
-                                        },
-                                    }
+    #[test]
+    fn open_options() {
+        let filename = "open-options.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, b"kept").unwrap();
 
-                                    process::exit(7);
-                                }
-                                Err(_) => {
-                                    panic!("Error forking tests :(");
-                                }
-                            }
+        let mut options = OpenOptions::new();
+        options.append(true);
+        let mut filelock = FileLock::new(filename)
+            .writeable(true)
+            .open_options(options.clone())
+            .lock()
+            .unwrap();
+        filelock.write_all(b", appended").unwrap();
+        drop(filelock);
+        assert_eq!(std::fs::read(filename).unwrap(), b"kept, appended");
 
-                            let _ = remove_file(&filename);
-                        }
-                    }
-                }
-            }
+        options.create_new(true);
+        let err = FileLock::new(filename)
+            .open_options(options)
+            .lock()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn append() {
+        let filename = "append.test";
+        let _ = remove_file(filename);
+        std::fs::write(filename, b"first\n").unwrap();
+
+        let lock = || {
+            FileLock::new(filename)
+                .blocking(true)
+                .writeable(true)
+                .append(true)
+                .lock()
+                .unwrap()
+        };
+        let holder = hold(|| {
+            let mut filelock = lock();
+            filelock.write_all(b"second\n").unwrap();
+            filelock
+        });
+        let releaser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            drop(holder);
+        });
+        let mut filelock = lock();
+        releaser.join().unwrap();
+        filelock.seek(SeekFrom::Start(0)).unwrap();
+        filelock.write_all(b"third\n").unwrap();
+        drop(filelock);
+        assert_eq!(std::fs::read(filename).unwrap(), b"first\nsecond\nthird\n");
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn create_new() {
+        let filename = "builder-create-new.test";
+        let _ = remove_file(filename);
+
+        let builder =
+            || FileLock::new(filename).writeable(true).create_new(true);
+        let filelock = builder().lock().unwrap();
+        let err = builder().lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        drop(filelock);
+        let err = builder().lock_detailed().unwrap_err();
+        assert!(matches!(err, FileLockError::Open(_)));
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn truncate() {
+        let filename = "truncate.test";
+        let _ = remove_file(filename);
+
+        let holder = hold(|| {
+            let mut filelock = FileLock::lock_exclusive(filename).unwrap();
+            filelock.write_all(b"in progress").unwrap();
+            filelock
+        });
+        let waiter = std::thread::spawn(move || {
+            FileLock::new(filename)
+                .blocking(true)
+                .writeable(true)
+                .truncate(true)
+                .lock()
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(in_child(
+            || std::fs::read(filename).unwrap() == b"in progress"
+        ));
+        drop(holder);
+        let filelock = waiter.join().unwrap().unwrap();
+        assert_eq!(filelock.metadata().unwrap().len(), 0);
+        drop(filelock);
+
+        let err = FileLock::new(filename).truncate(true).lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let filename = "mode.test";
+        let _ = remove_file(filename);
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .mode(0o600)
+            .lock()
+            .unwrap();
+        let mode = filelock.metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn create() {
+        let filename = "create.test";
+        let _ = remove_file(filename);
+
+        let builder = || FileLock::new(filename).writeable(true).create(false);
+        let err = builder().lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!Path::new(filename).exists());
+
+        std::fs::write(filename, b"").unwrap();
+        drop(builder().lock().unwrap());
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn exclusive() {
+        let filename = "exclusive.test";
+        let _ = remove_file(filename);
+
+        let builder = || FileLock::new(filename).writeable(true);
+        let filelock = builder().exclusive(false).lock().unwrap();
+        filelock.write_at(b"record", 8).unwrap();
+        assert!(in_child(|| {
+            builder().exclusive(false).lock().is_ok()
+                && builder().lock().unwrap_err().kind() == ErrorKind::WouldBlock
+        }));
+        drop(filelock);
+
+        let err = builder()
+            .exclusive(false)
+            .truncate(true)
+            .lock()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn cloexec() {
+        let filename = "cloexec.test";
+        let _ = remove_file(filename);
+
+        let fd_flags = |filelock: &FileLock| {
+            let flags = fcntl(filelock.as_raw_fd(), FcntlArg::F_GETFD);
+            FdFlag::from_bits_truncate(flags.unwrap())
+        };
+        let builder = || FileLock::new(filename).writeable(true);
+        let filelock = builder().lock().unwrap();
+        assert!(fd_flags(&filelock).contains(FdFlag::FD_CLOEXEC));
+        drop(filelock);
+        let filelock = builder().cloexec(false).lock().unwrap();
+        assert!(!fd_flags(&filelock).contains(FdFlag::FD_CLOEXEC));
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn new_at() {
+        let dir = "new-at.test";
+        let _ = std::fs::remove_dir_all(dir);
+        std::fs::create_dir(dir).unwrap();
+        let dirfd = File::open(dir).unwrap();
+
+        let filelock = FileLock::new_at(&dirfd, "state")
+            .writeable(true)
+            .lock()
+            .unwrap();
+        assert!(
+            same_file(&filelock.file, &Path::new(dir).join("state")).unwrap()
+        );
+        assert!(in_child(|| {
+            FileLock::new_at(&dirfd, "/state")
+                .writeable(true)
+                .lock()
+                .unwrap_err()
+                .kind()
+                == ErrorKind::WouldBlock
+        }));
+        drop(filelock);
+
+        let builder = FileLock::new_at(&dirfd, "state").writeable(true);
+        let err = builder.on_drop(OnDrop::Delete).lock().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn adopt_raw_fd() {
+        let filename = "adopt.test";
+        let _ = remove_file(filename);
+        let locked = || {
+            in_child(|| FileLock::new(filename).writeable(true).lock().is_err())
+        };
+
+        let builder = || FileLock::new(filename).writeable(true).ofd(true);
+        let fd = builder().lock().unwrap().into_raw_fd();
+        assert!(locked());
+        let filelock = unsafe { builder().adopt_raw_fd(fd) }.unwrap();
+        assert!(filelock.is_locked());
+        assert!(locked());
+        drop(filelock);
+        assert!(!locked());
+
+        let err = unsafe { builder().link_lockfile(true).adopt_raw_fd(-1) }
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn try_clone() {
+        let filename = "try-clone.test";
+        let _ = remove_file(filename);
+        let locked = |flock| {
+            in_child(|| {
+                let builder = FileLock::new(filename).writeable(true);
+                builder.flock(flock).lock().is_err()
+            })
+        };
+
+        let filelock = FileLock::new(filename)
+            .writeable(true)
+            .flock(true)
+            .lock()
+            .unwrap();
+        let clone = filelock.try_clone().unwrap();
+        assert!(!clone.is_locked());
+        drop(clone);
+        assert!(locked(true));
+        drop(filelock);
+        assert!(!locked(true));
+
+        #[cfg(target_os = "linux")]
+        {
+            let builder = FileLock::new(filename).writeable(true).ofd(true);
+            let filelock = builder.lock().unwrap();
+            drop(filelock.try_clone().unwrap());
+            assert!(locked(false));
+            drop(filelock);
+            assert!(!locked(false));
         }
+
+        let filelock = FileLock::lock_exclusive(filename).unwrap();
+        let err = filelock.try_clone().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(locked(false));
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[test]
+    fn buf_reader() {
+        let filename = "buf-reader.test";
+        let _ = remove_file(filename);
+
+        let mut writer = FileLock::lock_exclusive(filename)
+            .unwrap()
+            .into_buf_writer();
+        writer.write_all(b"one\ntwo\n").unwrap();
+        let filelock = writer.into_inner().unwrap();
+        assert!(filelock.is_locked());
+        assert_eq!(filelock.metadata().unwrap().len(), 8);
+        drop(filelock);
+
+        let mut reader =
+            FileLock::lock_shared(filename).unwrap().into_buf_reader();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "one\n");
+        let filelock = reader.into_inner();
+        assert!(filelock.is_locked());
+        drop(filelock);
+        let _ = remove_file(filename);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn map() {
+        let filename = "map.test";
+        let _ = remove_file(filename);
+
+        let mut filelock = FileLock::lock_exclusive(filename).unwrap();
+        assert!(unsafe { filelock.map() }.unwrap().is_empty());
+        filelock.write_all(b"abc").unwrap();
+        let mut map = unsafe { filelock.map_mut() }.unwrap();
+        map[1] = b'x';
+        map.flush().unwrap();
+        drop(map);
+        let mut buf = [0; 3];
+        filelock.read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"axc");
+        drop(filelock);
+
+        let filelock = FileLock::lock_shared(filename).unwrap();
+        assert_eq!(&unsafe { filelock.map() }.unwrap()[..], b"axc");
+        drop(filelock);
+        let _ = remove_file(filename);
     }
 }
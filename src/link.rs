@@ -0,0 +1,147 @@
+//! Whole-file locks made by hard linking a lock file into place, for
+//! network filesystems where `fcntl()` locks can't be trusted
+
+use crate::{cver, interrupt, Block, LockType};
+use std::{
+    ffi::OsString,
+    fs::OpenOptions,
+    io::{prelude::*, Error, ErrorKind, Result},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Longest sleep between two attempts at a contended lock file
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The `<path>.lock` file of one handle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LinkLock {
+    lockfile: PathBuf,
+    /// `hostname:pid:n`, unique to the handle
+    id: String,
+}
+
+impl LinkLock {
+    pub(crate) fn new(path: &Path) -> Result<LinkLock> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let mut buf = [0; 256];
+        let hostname = nix::unistd::gethostname(&mut buf).map_err(cver)?;
+        let mut lockfile = OsString::from(path);
+        lockfile.push(".lock");
+        Ok(LinkLock {
+            lockfile: lockfile.into(),
+            id: format!(
+                "{}:{}:{}",
+                hostname.to_string_lossy(),
+                std::process::id(),
+                COUNTER.fetch_add(1, Ordering::Relaxed)
+            ),
+        })
+    }
+
+    /// Take or release the lock file, waiting as long as `block` says
+    ///
+    /// There is a single kind of lock, so read locks exclude each other too.
+    pub(crate) fn apply(
+        &self,
+        lock_type: LockType,
+        block: Block,
+    ) -> Result<()> {
+        if lock_type == LockType::Unlocked {
+            return self.unlock();
+        }
+        let (timeout, cancel) = match block {
+            Block::No => (Some(Duration::ZERO), None),
+            Block::Forever(_) => (interrupt::default_max_wait(), None),
+            Block::For(timeout) => (Some(timeout), None),
+            Block::Cancellable(cancel, timeout) => {
+                (timeout.or_else(interrupt::default_max_wait), Some(cancel))
+            }
+        };
+        let started = Instant::now();
+        let mut interval = Duration::from_millis(10);
+        loop {
+            if cancel
+                .as_ref()
+                .map_or(false, |cancel| cancel.is_cancelled())
+            {
+                return Err(Error::new(
+                    ErrorKind::Interrupted,
+                    "lock wait cancelled",
+                ));
+            }
+            if self.try_lock()? {
+                return Ok(());
+            }
+            let mut sleep = interval;
+            if let Some(timeout) = timeout {
+                let remaining = timeout.saturating_sub(started.elapsed());
+                if timeout == Duration::ZERO {
+                    return Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        "lock file is held by someone else",
+                    ));
+                } else if remaining == Duration::ZERO {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        format!(
+                            "timed out waiting for lock after {:?}",
+                            started.elapsed()
+                        ),
+                    ));
+                }
+                sleep = sleep.min(remaining);
+            }
+            thread::sleep(sleep);
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        }
+    }
+
+    /// Link a new file holding our id to the lock file path, which fails if
+    /// it exists
+    fn try_lock(&self) -> Result<bool> {
+        if self.is_ours()? {
+            return Ok(true);
+        }
+        let mut temp = self.lockfile.clone().into_os_string();
+        temp.push(format!(".{}.tmp", self.id));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        let written =
+            writeln!(file, "{}", self.id).and_then(|_| file.sync_all());
+        let linked =
+            written.and_then(|_| std::fs::hard_link(&temp, &self.lockfile));
+        // over NFS, link() may report an error although it succeeded, which
+        // the link count of the temporary file tells
+        let nlink = file.metadata().map(|metadata| metadata.nlink());
+        let _ = std::fs::remove_file(&temp);
+        match (linked, nlink) {
+            (_, Ok(2)) => Ok(true),
+            (Ok(()), _) => Ok(true),
+            (Err(e), _) if e.kind() == ErrorKind::AlreadyExists => Ok(false),
+            (Err(e), _) => Err(e),
+        }
+    }
+
+    fn is_ours(&self) -> Result<bool> {
+        match std::fs::read_to_string(&self.lockfile) {
+            Ok(contents) => Ok(contents.trim_end() == self.id),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Remove the lock file, unless someone else broke and took it
+    fn unlock(&self) -> Result<()> {
+        if self.is_ours()? {
+            std::fs::remove_file(&self.lockfile)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+//! Memory maps of locked files
+
+use crate::{cver, FileLock};
+use nix::{
+    libc::c_void,
+    sys::mman::{mmap, msync, munmap, MapFlags, MsFlags, ProtFlags},
+};
+use std::{
+    convert::TryFrom,
+    io::{Error, ErrorKind, Result},
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    os::unix::io::AsRawFd,
+    ptr, slice,
+};
+
+/// A shared, read-only memory map of a locked file, made with
+/// [`FileLock::map`](struct.FileLock.html#method.map)
+///
+/// It borrows the [`FileLock`](struct.FileLock.html), so the `FileLock`
+/// can't be dropped while the map is in use. It derefs to the contents of
+/// the file, as long as the file was when mapped.
+#[derive(Debug)]
+pub struct LockedMap<'a> {
+    map: Mapping,
+    filelock: PhantomData<&'a FileLock>,
+}
+
+/// A shared, writeable memory map of a locked file, made with
+/// [`FileLock::map_mut`](struct.FileLock.html#method.map_mut)
+///
+/// Like [`LockedMap`](struct.LockedMap.html), but changes to it go to the
+/// file. [`flush`](#method.flush) waits until they are written.
+#[derive(Debug)]
+pub struct LockedMapMut<'a> {
+    map: Mapping,
+    filelock: PhantomData<&'a mut FileLock>,
+}
+
+#[derive(Debug)]
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+// Safety: the mapping is only handed out as `&[u8]` or `&mut [u8]` through
+// `&self` or `&mut self`, and `FileLock::map` and `map_mut` are unsafe as
+// the file itself can still change under it
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(filelock: &FileLock, prot: ProtFlags) -> Result<Mapping> {
+        let len =
+            usize::try_from(filelock.metadata()?.len()).map_err(|_| {
+                Error::new(ErrorKind::InvalidInput, "file too large to map")
+            })?;
+        if len == 0 {
+            // mmap() rejects empty maps
+            return Ok(Mapping {
+                ptr: ptr::NonNull::dangling().as_ptr(),
+                len,
+            });
+        }
+        let ptr = unsafe {
+            mmap(
+                ptr::null_mut(),
+                len,
+                prot,
+                MapFlags::MAP_SHARED,
+                filelock.as_raw_fd(),
+                0,
+            )
+        }
+        .map_err(cver)?;
+        Ok(Mapping { ptr, len })
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            let _ = unsafe { munmap(self.ptr, self.len) };
+        }
+    }
+}
+
+impl<'a> LockedMap<'a> {
+    pub(crate) fn new(filelock: &'a FileLock) -> Result<LockedMap<'a>> {
+        Ok(LockedMap {
+            map: Mapping::new(filelock, ProtFlags::PROT_READ)?,
+            filelock: PhantomData,
+        })
+    }
+}
+
+impl Deref for LockedMap<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.map.bytes()
+    }
+}
+
+impl<'a> LockedMapMut<'a> {
+    pub(crate) fn new(filelock: &'a mut FileLock) -> Result<LockedMapMut<'a>> {
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        Ok(LockedMapMut {
+            map: Mapping::new(filelock, prot)?,
+            filelock: PhantomData,
+        })
+    }
+
+    /// Write changes to the file and wait until they are done
+    pub fn flush(&self) -> Result<()> {
+        if self.map.len == 0 {
+            return Ok(());
+        }
+        unsafe { msync(self.map.ptr, self.map.len, MsFlags::MS_SYNC) }
+            .map_err(cver)
+    }
+}
+
+impl Deref for LockedMapMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.map.bytes()
+    }
+}
+
+impl DerefMut for LockedMapMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.map.bytes_mut()
+    }
+}
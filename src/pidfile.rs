@@ -0,0 +1,324 @@
+//! Pidfiles and single-instance guards held for the lifetime of a process
+
+use crate::{
+    cver, first_conflict, same_file, DropAction, FileLock, SingletonResult,
+};
+use nix::{errno::Errno, libc, sys::signal::kill, unistd::Pid};
+#[cfg(target_os = "linux")]
+use std::time::{Duration, SystemTime};
+use std::{
+    fs::File,
+    io::{prelude::*, Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+/// A pidfile holding the PID of this process under an exclusive lock
+///
+/// [`create`](#method.create) fails if another instance already holds the
+/// pidfile. The lock is kept for as long as the `PidFile` lives, so keep it
+/// around until the daemon exits. When it is dropped, the file is removed
+/// while still locked, unless it has been replaced meanwhile, then
+/// released. If the process dies instead, the lock goes away with it and the
+/// stale file is simply taken over by the next instance.
+///
+/// # Examples
+///
+///```
+///use file_locker::PidFile;
+///use std::io::{ErrorKind, Result};
+///
+///fn main() -> Result<()> {
+///    let pidfile = match PidFile::create("mydaemon.pid") {
+///        Ok(pidfile) => pidfile,
+///        Err(e) if e.kind() == ErrorKind::WouldBlock => {
+///            let pid = PidFile::read_pid("mydaemon.pid")?;
+///            eprintln!("already running as {:?}", pid);
+///            return Ok(());
+///        }
+///        Err(e) => return Err(e),
+///    };
+///    // run the daemon
+///    drop(pidfile);
+///    Ok(())
+///}
+///```
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+    filelock: FileLock,
+}
+
+impl PidFile {
+    /// Lock the pidfile at `path`, creating it if needed, and write the PID
+    /// of this process to it
+    ///
+    /// Fails with an error of kind `ErrorKind::WouldBlock`, naming the other
+    /// instance's PID if known, if it is held by another process.
+    pub fn create(path: impl AsRef<Path>) -> Result<PidFile> {
+        let path = path.as_ref().to_owned();
+        loop {
+            match FileLock::singleton(&path)? {
+                SingletonResult::Acquired(mut filelock) => {
+                    // the previous holder removing the file as it exits, or
+                    // a stale pidfile being broken, may have unlinked it
+                    // before we locked it, leaving us a lock nobody else
+                    // will see, so start over with whatever the path names
+                    if !names(&path, &filelock)? {
+                        continue;
+                    }
+                    filelock.on_drop = DropAction::Delete(path.clone());
+                    return Ok(PidFile { path, filelock });
+                }
+                SingletonResult::AlreadyRunning { pid } => {
+                    return Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        match pid {
+                            Some(pid) => {
+                                format!("already running as PID {}", pid)
+                            }
+                            None => "already running".to_string(),
+                        },
+                    ))
+                }
+            }
+        }
+    }
+
+    /// The PID of the process holding the pidfile at `path`, or `None` if
+    /// nobody holds it
+    ///
+    /// The PID is read from the file, or queried with `F_GETLK` if the file
+    /// doesn't hold one yet. A leftover file from an instance that died
+    /// gives `None`. `F_GETLK` never reports this process' own locks, so
+    /// this is meant for other processes, and opening and closing the file
+    /// would release the lock of a `PidFile` held by this process anyway.
+    pub fn read_pid(path: impl AsRef<Path>) -> Result<Option<libc::pid_t>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let holder = match first_conflict(&file, 0, None)? {
+            Some(lock) => lock.l_pid,
+            None => return Ok(None),
+        };
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        Ok(Some(contents.trim().parse().unwrap_or(holder)))
+    }
+
+    /// Whether the pidfile at `path` names a process that is gone
+    ///
+    /// That is the case if no process with the PID stored in the file
+    /// exists, or, on Linux, if the process with that PID was started after
+    /// the file was last written, meaning the PID was reused. A missing file
+    /// or one without a PID isn't stale.
+    ///
+    /// This is about the file's contents only: the pidfile may still be
+    /// locked, e.g. by a child that inherited the descriptor of an instance
+    /// that crashed, or not be locked at all if it was written by a program
+    /// that doesn't lock it.
+    pub fn is_stale(path: impl AsRef<Path>) -> Result<bool> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        let pid = match contents.trim().parse() {
+            Ok(pid) if pid > 0 => Pid::from_raw(pid),
+            _ => return Ok(false),
+        };
+        match kill(pid, None) {
+            Ok(()) | Err(Errno::EPERM) => {}
+            Err(Errno::ESRCH) => return Ok(true),
+            Err(e) => return Err(cver(e)),
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(started) = start_time(pid) {
+            let written = std::fs::metadata(&path)?.modified()?;
+            // allow for the clock tick resolution of the start time
+            return Ok(started > written + Duration::from_secs(1));
+        }
+        Ok(false)
+    }
+
+    /// Like [`create`](#method.create), but taking over a pidfile held by
+    /// another process if [`is_stale`](#method.is_stale) says it is stale
+    ///
+    /// The stale file is removed and a new one created in its place, so
+    /// whoever still holds a lock on the old one no longer shares it with
+    /// anyone. Processes breaking the same pidfile take turns by locking its
+    /// directory with `flock()`, and the file is only removed if the path
+    /// still names the one found stale, so one of them can't remove the
+    /// pidfile another has just created.
+    pub fn create_breaking_stale(path: impl AsRef<Path>) -> Result<PidFile> {
+        let path = path.as_ref();
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        let mut result = PidFile::create(path);
+        loop {
+            match result {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    let _turn = FileLock::new(dir)
+                        .flock(true)
+                        .exclusive(true)
+                        .blocking(true)
+                        .lock()?;
+                    let stale = match File::open(path) {
+                        Ok(stale) => stale,
+                        Err(e) if e.kind() == ErrorKind::NotFound => {
+                            result = PidFile::create(path);
+                            continue;
+                        }
+                        Err(e) => return Err(e),
+                    };
+                    if !PidFile::is_stale(path)? {
+                        return Err(e);
+                    }
+                    if names(path, &stale)? {
+                        match std::fs::remove_file(path) {
+                            Err(e) if e.kind() != ErrorKind::NotFound => {
+                                return Err(e)
+                            }
+                            _ => {}
+                        }
+                    }
+                    result = PidFile::create(path);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The pidfile's path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The lock held on the pidfile
+    pub fn file_lock(&self) -> &FileLock {
+        &self.filelock
+    }
+}
+
+/// Whether `path` still names `file`
+fn names(path: &Path, file: &File) -> Result<bool> {
+    match same_file(file, path) {
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        result => result,
+    }
+}
+
+/// Guard making sure only one instance of an application runs per user
+///
+/// [`acquire`](#method.acquire) locks a pidfile named after the
+/// application, in `$XDG_RUNTIME_DIR` if set, or else in the temporary
+/// directory with the user's id in the name, see
+/// [`FileLock::singleton`](struct.FileLock.html#method.singleton). If this
+/// is the first instance, the lock is held until the guard is dropped.
+///
+/// # Examples
+///
+///```
+///use file_locker::SingleInstance;
+///use std::io::Result;
+///
+///fn main() -> Result<()> {
+///    let instance = SingleInstance::acquire("myapp")?;
+///    if instance.already_running() {
+///        eprintln!("myapp is already running as {:?}", instance.other_pid());
+///        return Ok(());
+///    }
+///    // run the application while holding instance
+///    Ok(())
+///}
+///```
+#[derive(Debug)]
+pub struct SingleInstance {
+    path: PathBuf,
+    filelock: Option<FileLock>,
+    other_pid: Option<libc::pid_t>,
+}
+
+impl SingleInstance {
+    /// Check whether `app_name` is already running for this user, and
+    /// become its running instance if not
+    ///
+    /// `app_name` must be usable as a file name. Errors are only returned if
+    /// the lock file couldn't be opened or probed.
+    pub fn acquire(app_name: &str) -> Result<SingleInstance> {
+        if app_name.is_empty()
+            || app_name.contains('/')
+            || app_name == "."
+            || app_name == ".."
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "application name must be a valid file name",
+            ));
+        }
+        let path = match std::env::var_os("XDG_RUNTIME_DIR") {
+            Some(dir) if Path::new(&dir).is_dir() => {
+                Path::new(&dir).join(format!("{}.lock", app_name))
+            }
+            _ => std::env::temp_dir().join(format!(
+                "{}-{}.lock",
+                app_name,
+                nix::unistd::getuid()
+            )),
+        };
+        Ok(match FileLock::singleton(&path)? {
+            SingletonResult::Acquired(filelock) => SingleInstance {
+                path,
+                filelock: Some(filelock),
+                other_pid: None,
+            },
+            SingletonResult::AlreadyRunning { pid } => SingleInstance {
+                path,
+                filelock: None,
+                other_pid: pid,
+            },
+        })
+    }
+
+    /// Whether another instance holds the lock, in which case this one
+    /// should exit
+    pub fn already_running(&self) -> bool {
+        self.filelock.is_none()
+    }
+
+    /// PID of the other instance if it is running and could be determined
+    pub fn other_pid(&self) -> Option<libc::pid_t> {
+        self.other_pid
+    }
+
+    /// The lock file's path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// When the process `pid` was started, from `/proc`
+#[cfg(target_os = "linux")]
+fn start_time(pid: Pid) -> Option<SystemTime> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // the command name in parentheses may contain spaces, the start time in
+    // clock ticks since boot is the 20th field after it
+    let after_comm = &stat[stat.rfind(')')? + 1..];
+    let ticks: u64 = after_comm.split_whitespace().nth(19)?.parse().ok()?;
+    let boot: u64 = std::fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    let hz = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if hz <= 0 {
+        return None;
+    }
+    let since_boot = Duration::from_secs_f64(ticks as f64 / hz as f64);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(boot) + since_boot)
+}
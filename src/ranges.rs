@@ -0,0 +1,129 @@
+use crate::LockType;
+
+/// A locked byte range, `end` being `None` when the range extends to the end
+/// of the file however far it grows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Range {
+    pub start: u64,
+    pub end: Option<u64>,
+    pub lock_type: LockType,
+}
+
+/// Byte ranges held through one `FileLock`
+///
+/// Ranges are kept sorted, non-overlapping and coalesced, the same way the
+/// kernel splits and merges the record locks of a process.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct RangeMap {
+    ranges: Vec<Range>,
+}
+
+impl RangeMap {
+    /// Record that `[start, end)` is now locked as `lock_type`, replacing
+    /// whatever was held over that span
+    pub fn set(&mut self, start: u64, end: Option<u64>, lock_type: LockType) {
+        let mut ranges = Vec::with_capacity(self.ranges.len() + 2);
+        for range in self.ranges.drain(..) {
+            if range.start < start {
+                ranges.push(Range {
+                    end: Some(range.end.map_or(start, |e| e.min(start))),
+                    ..range
+                });
+            }
+            if let Some(end) = end {
+                if !matches!(range.end, Some(e) if e <= end) {
+                    ranges.push(Range {
+                        start: range.start.max(end),
+                        ..range
+                    });
+                }
+            }
+        }
+        if lock_type != LockType::Unlocked {
+            ranges.push(Range {
+                start,
+                end,
+                lock_type,
+            });
+        }
+        ranges.sort_by_key(|range| range.start);
+
+        for range in ranges {
+            match self.ranges.last_mut() {
+                Some(last)
+                    if last.end == Some(range.start)
+                        && last.lock_type == range.lock_type =>
+                {
+                    last.end = range.end
+                }
+                _ => self.ranges.push(range),
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Range> {
+        self.ranges.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use LockType::*;
+
+    fn map(ops: &[(u64, Option<u64>, LockType)]) -> Vec<Range> {
+        let mut map = RangeMap::default();
+        for &(start, end, lock_type) in ops {
+            map.set(start, end, lock_type);
+        }
+        map.iter().copied().collect()
+    }
+
+    fn range(start: u64, end: Option<u64>, lock_type: LockType) -> Range {
+        Range {
+            start,
+            end,
+            lock_type,
+        }
+    }
+
+    #[test]
+    fn split_and_merge() {
+        assert_eq!(
+            map(&[(0, Some(100), Write), (50, Some(100), Unlocked)]),
+            vec![range(0, Some(50), Write)]
+        );
+        assert_eq!(
+            map(&[(0, Some(100), Write), (40, Some(60), Read)]),
+            vec![
+                range(0, Some(40), Write),
+                range(40, Some(60), Read),
+                range(60, Some(100), Write),
+            ]
+        );
+        assert_eq!(
+            map(&[
+                (0, Some(10), Read),
+                (20, Some(30), Read),
+                (10, Some(20), Read)
+            ]),
+            vec![range(0, Some(30), Read)]
+        );
+        assert_eq!(
+            map(&[(10, None, Write), (0, Some(20), Read)]),
+            vec![range(0, Some(20), Read), range(20, None, Write)]
+        );
+        assert_eq!(
+            map(&[
+                (10, Some(20), Write),
+                (30, Some(40), Read),
+                (0, None, Unlocked)
+            ]),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Replacing a file's contents atomically under its lock
+
+use crate::{same_file, FileLock};
+use std::{
+    fs::{File, OpenOptions},
+    io::{prelude::*, Error, ErrorKind, IoSlice, Result},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// New contents for a file, made with
+/// [`FileLock::replace`](struct.FileLock.html#method.replace)
+///
+/// Whatever is written goes to a temporary file in the same directory. On
+/// [`commit`](#method.commit) it is synced and renamed over the target, so
+/// readers see either the old or the new contents in full, never a partial
+/// write. [`abort`](#method.abort), or dropping the `Replacement` without
+/// committing, removes the temporary file and leaves the target alone.
+///
+/// The target stays exclusively locked until then, which serializes
+/// writers going through `FileLock::replace`.
+#[derive(Debug)]
+pub struct Replacement {
+    filelock: FileLock,
+    target: PathBuf,
+    temp: File,
+    temp_path: PathBuf,
+    done: bool,
+}
+
+impl Replacement {
+    pub(crate) fn new(target: &Path) -> Result<Replacement> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let name = target.file_name().ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "path has no file name")
+        })?;
+        let filelock = loop {
+            let filelock = FileLock::lock_exclusive(target)?;
+            // a writer that committed while we waited renamed a new file
+            // over the one we locked
+            if same_file(&filelock, target)? {
+                break filelock;
+            }
+        };
+        let temp_path = target.with_file_name(format!(
+            ".{}.{}.{}.tmp",
+            name.to_string_lossy(),
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        temp.set_permissions(filelock.metadata()?.permissions())?;
+        Ok(Replacement {
+            filelock,
+            target: target.to_owned(),
+            temp,
+            temp_path,
+            done: false,
+        })
+    }
+
+    /// The temporary file holding the new contents
+    pub fn file(&self) -> &File {
+        &self.temp
+    }
+
+    /// The lock held on the target, whose `File` still has the old contents
+    pub fn file_lock(&self) -> &FileLock {
+        &self.filelock
+    }
+
+    /// Sync the new contents and rename them over the target, then release
+    /// the lock
+    pub fn commit(mut self) -> Result<()> {
+        self.temp.sync_all()?;
+        std::fs::rename(&self.temp_path, &self.target)?;
+        self.done = true;
+        // make the rename itself durable
+        let dir = match self.target.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    /// Discard the new contents, then release the lock
+    pub fn abort(mut self) -> Result<()> {
+        self.done = true;
+        std::fs::remove_file(&self.temp_path)
+    }
+}
+
+impl Write for Replacement {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.temp.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        self.temp.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.temp.flush()
+    }
+}
+
+impl Drop for Replacement {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+    }
+}
@@ -0,0 +1,172 @@
+//! A reader-writer lock over a file, shaped like `std::sync::RwLock`
+
+use crate::{FileLock, FileLockBuilder};
+use std::{
+    io::{prelude::*, IoSliceMut, Result, SeekFrom},
+    ops::{Deref, DerefMut},
+    os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+/// A reader-writer lock on a whole file
+///
+/// Shared and exclusive access are taken with [`read`](#method.read) and
+/// [`write`](#method.write), which return different guard types, so the
+/// kind of lock held is visible in the types rather than in a `bool`. Only
+/// a [`WriteGuard`](struct.WriteGuard.html) gives mutable access to the
+/// file. The lock is released when the guard is dropped.
+///
+/// On Linux, open file description locks are used (see
+/// [`FileLockBuilder::ofd`](struct.FileLockBuilder.html#method.ofd)), so
+/// guards exclude each other within the process too, and dropping one
+/// doesn't release the others. Elsewhere record locks are used, which only
+/// exclude other processes.
+///
+/// # Examples
+///
+///```
+///use file_locker::FileRwLock;
+///use std::io::prelude::*;
+///use std::io::Result;
+///
+///fn main() -> Result<()> {
+///    let lock = FileRwLock::new("myfile.txt");
+///    lock.write()?.write_all(b"Hello, World!")?;
+///
+///    let mut contents = String::new();
+///    lock.read()?.read_to_string(&mut contents)?;
+///    Ok(())
+///}
+///```
+#[derive(Debug, Clone)]
+pub struct FileRwLock {
+    path: PathBuf,
+}
+
+impl FileRwLock {
+    /// A lock on the file at `path`, which is only opened when locking
+    pub fn new(path: impl AsRef<Path>) -> FileRwLock {
+        FileRwLock {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    /// The locked file's path
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Wait for shared access to the file, which must exist
+    pub fn read(&self) -> Result<ReadGuard> {
+        let filelock = self.builder(false).blocking(true).lock()?;
+        Ok(ReadGuard { filelock })
+    }
+
+    /// Wait for exclusive access to the file, creating it if needed
+    pub fn write(&self) -> Result<WriteGuard> {
+        let filelock = self.builder(true).blocking(true).lock()?;
+        Ok(WriteGuard { filelock })
+    }
+
+    /// Like [`read`](#method.read), but failing with an error of kind
+    /// `ErrorKind::WouldBlock` instead of waiting for a writer
+    pub fn try_read(&self) -> Result<ReadGuard> {
+        let filelock = self.builder(false).try_lock()?;
+        Ok(ReadGuard { filelock })
+    }
+
+    /// Like [`write`](#method.write), but failing with an error of kind
+    /// `ErrorKind::WouldBlock` instead of waiting for other guards
+    pub fn try_write(&self) -> Result<WriteGuard> {
+        let filelock = self.builder(true).try_lock()?;
+        Ok(WriteGuard { filelock })
+    }
+
+    fn builder(&self, writeable: bool) -> FileLockBuilder<&Path> {
+        let builder = FileLock::new(self.path.as_path()).writeable(writeable);
+        #[cfg(target_os = "linux")]
+        let builder = builder.ofd(true);
+        builder
+    }
+}
+
+/// Shared access to a file, from [`FileRwLock::read`](struct.FileRwLock.html#method.read)
+///
+/// The file is open read-only. The guard derefs to the underlying
+/// [`FileLock`](struct.FileLock.html) and can be read from and seeked.
+#[derive(Debug)]
+pub struct ReadGuard {
+    filelock: FileLock,
+}
+
+impl Deref for ReadGuard {
+    type Target = FileLock;
+
+    fn deref(&self) -> &FileLock {
+        &self.filelock
+    }
+}
+
+impl Read for ReadGuard {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.filelock.read(buf)
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
+        self.filelock.read_vectored(bufs)
+    }
+}
+
+impl Seek for ReadGuard {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.filelock.seek(pos)
+    }
+}
+
+impl AsRawFd for ReadGuard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.filelock.as_raw_fd()
+    }
+}
+
+impl AsFd for ReadGuard {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.filelock.as_fd()
+    }
+}
+
+/// Exclusive access to a file, from [`FileRwLock::write`](struct.FileRwLock.html#method.write)
+///
+/// The guard derefs mutably to the underlying
+/// [`FileLock`](struct.FileLock.html), so it can be read, written and
+/// seeked.
+#[derive(Debug)]
+pub struct WriteGuard {
+    filelock: FileLock,
+}
+
+impl Deref for WriteGuard {
+    type Target = FileLock;
+
+    fn deref(&self) -> &FileLock {
+        &self.filelock
+    }
+}
+
+impl DerefMut for WriteGuard {
+    fn deref_mut(&mut self) -> &mut FileLock {
+        &mut self.filelock
+    }
+}
+
+impl AsRawFd for WriteGuard {
+    fn as_raw_fd(&self) -> RawFd {
+        self.filelock.as_raw_fd()
+    }
+}
+
+impl AsFd for WriteGuard {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.filelock.as_fd()
+    }
+}